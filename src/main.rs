@@ -1,40 +1,105 @@
 #![no_std]
 #![no_main]
+#![feature(abi_x86_interrupt)]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::tests::run)]
+#![reexport_test_harness_main = "test_main"]
 
+use core::fmt::Write;
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 
 const VGA_BUFFER: *mut u8 = 0xb8000 as *mut u8;
 const WIDTH: usize = 80;
+const DEFAULT_HEIGHT: usize = 25;
+const MAX_HEIGHT: usize = 50;
 
+// Number of text rows in the current VGA mode (25 or 50)
+static mut HEIGHT: usize = DEFAULT_HEIGHT;
+
+fn height() -> usize {
+    unsafe { HEIGHT }
+}
+
+// Rows available to the shell; the bottom row is the status bar
+fn text_rows() -> usize {
+    height() - 1
+}
+
+// memset and memcpy work 8 bytes at a time once the destination is
+// aligned, with byte loops for the unaligned head and the tail.
+const WORD: usize = core::mem::size_of::<u64>();
+
+/// # Safety
+/// `s` must be valid for writes of `n` bytes.
 #[no_mangle]
-pub extern "C" fn memset(s: *mut u8, c: i32, n: usize) -> *mut u8 {
+pub unsafe extern "C" fn memset(s: *mut u8, c: i32, n: usize) -> *mut u8 {
     let mut i = 0;
-    unsafe {
-        while i < n {
-            *s.add(i) = c as u8;
-            i += 1;
-        }
+    while i < n && !(s as usize + i).is_multiple_of(WORD) {
+        *s.add(i) = c as u8;
+        i += 1;
+    }
+    let word = (c as u8 as u64) * 0x0101_0101_0101_0101;
+    while i + WORD <= n {
+        (s.add(i) as *mut u64).write(word);
+        i += WORD;
+    }
+    while i < n {
+        *s.add(i) = c as u8;
+        i += 1;
     }
     s
 }
 
+/// # Safety
+/// `src` and `dest` must be valid for `n` bytes and must not overlap.
 #[no_mangle]
-pub extern "C" fn memcpy(dest: *mut u8, src: *const u8, n: usize) -> *mut u8 {
+pub unsafe extern "C" fn memcpy(dest: *mut u8, src: *const u8, n: usize) -> *mut u8 {
     let mut i = 0;
-    unsafe {
-        while i < n {
+    // words only help when both sides can be aligned together
+    if (dest as usize) % WORD == (src as usize) % WORD {
+        while i < n && !(dest as usize + i).is_multiple_of(WORD) {
             *dest.add(i) = *src.add(i);
             i += 1;
         }
+        while i + WORD <= n {
+            (dest.add(i) as *mut u64).write((src.add(i) as *const u64).read());
+            i += WORD;
+        }
+    }
+    while i < n {
+        *dest.add(i) = *src.add(i);
+        i += 1;
+    }
+    dest
+}
+
+/// Unlike memcpy, the regions may overlap: the copy runs backward when `dest`
+/// lies above `src`, so every byte is read before it is overwritten.
+///
+/// # Safety
+/// `src` and `dest` must be valid for `n` bytes; they may overlap.
+#[no_mangle]
+pub unsafe extern "C" fn memmove(dest: *mut u8, src: *const u8, n: usize) -> *mut u8 {
+    if (dest as usize) <= (src as usize) {
+        for i in 0..n {
+            *dest.add(i) = *src.add(i);
+        }
+    } else {
+        for i in (0..n).rev() {
+            *dest.add(i) = *src.add(i);
+        }
     }
     dest
 }
 
+/// # Safety
+/// `s1` and `s2` must be valid for reads of `n` bytes.
 #[no_mangle]
-pub extern "C" fn memcmp(s1: *const u8, s2: *const u8, n: usize) -> i32 {
+pub unsafe extern "C" fn memcmp(s1: *const u8, s2: *const u8, n: usize) -> i32 {
     for i in 0..n {
-        let a = unsafe { *s1.add(i) };
-        let b = unsafe { *s2.add(i) };
+        let a = *s1.add(i);
+        let b = *s2.add(i);
         if a != b {
             return a as i32 - b as i32;
         }
@@ -42,22 +107,156 @@ pub extern "C" fn memcmp(s1: *const u8, s2: *const u8, n: usize) -> i32 {
     0
 }
 
+// Every write to the text buffer goes through clear_screen, print_bytes_at
+// or put_char, and each holds this lock for the whole write. The lock is taken
+// with interrupts disabled, so code that is interrupted never holds it; that
+// makes with_vga safe to call from interrupt handlers too, and on one CPU the
+// spin only ever waits on a task switch that can't happen inside the closure.
+static VGA_LOCK: AtomicBool = AtomicBool::new(false);
+
+fn with_vga<R>(f: impl FnOnce() -> R) -> R {
+    let rflags: u64;
+    unsafe { core::arch::asm!("pushfq", "pop {}", "cli", out(reg) rflags); }
+    while VGA_LOCK.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+        core::hint::spin_loop();
+    }
+    let result = f();
+    VGA_LOCK.store(false, Ordering::Release);
+    // only re-enable interrupts if they were on before
+    if rflags & (1 << 9) != 0 {
+        unsafe { core::arch::asm!("sti"); }
+    }
+    result
+}
+
 fn clear_screen() {
-    for i in 0..(WIDTH * 25) {
+    with_vga(|| {
+        for i in 0..(WIDTH * height()) {
+            unsafe {
+                *VGA_BUFFER.add(i * 2) = b' ';
+                *VGA_BUFFER.add(i * 2 + 1) = TEXT_ATTR;
+            }
+        }
+    });
+}
+
+// Moves the text rows below the header up by `n`, blanking the rows that open
+// up above the status bar. Source and destination overlap, hence memmove.
+fn scroll_up(n: usize) {
+    let (top, bottom) = (1, text_rows());
+    let n = n.min(bottom - top);
+    let row_bytes = WIDTH * 2;
+    with_vga(|| {
         unsafe {
-            *VGA_BUFFER.add(i * 2) = b' ';
-            *VGA_BUFFER.add(i * 2 + 1) = 0x0f;
+            memmove(
+                VGA_BUFFER.add(top * row_bytes),
+                VGA_BUFFER.add((top + n) * row_bytes),
+                (bottom - top - n) * row_bytes,
+            );
         }
-    }
+        for i in (bottom - n) * WIDTH..bottom * WIDTH {
+            unsafe {
+                *VGA_BUFFER.add(i * 2) = b' ';
+                *VGA_BUFFER.add(i * 2 + 1) = TEXT_ATTR;
+            }
+        }
+    });
+}
+
+// The 16 VGA text colors, in attribute order. Backgrounds only take the
+// first eight unless blinking is turned off.
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+enum Color {
+    Black,
+    Blue,
+    Green,
+    Cyan,
+    Red,
+    Magenta,
+    Brown,
+    LightGrey,
+    DarkGrey,
+    LightBlue,
+    LightGreen,
+    LightCyan,
+    LightRed,
+    Pink,
+    Yellow,
+    White,
+}
+
+// Attribute byte for `fg` text on a `bg` background
+const fn attr(fg: Color, bg: Color) -> u8 {
+    (bg as u8) << 4 | fg as u8
 }
 
 fn print_at(s: &str, row: usize) {
-    for (i, byte) in s.bytes().enumerate() {
-        let idx = (row * WIDTH + i) * 2;
-        unsafe {
-            *VGA_BUFFER.add(idx) = byte;
-            *VGA_BUFFER.add(idx + 1) = 0x0f;
+    print_at_colored(s, row, Color::White, Color::Black);
+}
+
+fn print_at_colored(s: &str, row: usize, fg: Color, bg: Color) {
+    print_bytes_at(s.as_bytes(), row, attr(fg, bg));
+    serial_mirror(s.as_bytes());
+}
+
+fn print_bytes_at(s: &[u8], row: usize, attr: u8) {
+    print_bytes_at_col(s, row, 0, attr);
+}
+
+// Like print_bytes_at, but starting at column `col`
+fn print_bytes_at_col(s: &[u8], row: usize, col: usize, attr: u8) {
+    with_vga(|| {
+        for (i, &byte) in s.iter().enumerate() {
+            let idx = (row * WIDTH + col + i) * 2;
+            unsafe {
+                *VGA_BUFFER.add(idx) = byte;
+                *VGA_BUFFER.add(idx + 1) = attr;
+            }
         }
+    });
+}
+
+fn put_char(row: usize, col: usize, c: u8) {
+    let idx = (row * WIDTH + col) * 2;
+    with_vga(|| unsafe {
+        *VGA_BUFFER.add(idx) = c;
+        *VGA_BUFFER.add(idx + 1) = TEXT_ATTR;
+    });
+}
+
+// A screen line assembled with `write!`, for output that mixes text and numbers
+struct LineBuf {
+    buf: [u8; WIDTH],
+    len: usize,
+}
+
+impl LineBuf {
+    fn new() -> Self {
+        LineBuf { buf: [0; WIDTH], len: 0 }
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    // Raw bytes, e.g. code page 437 characters that aren't valid UTF-8
+    fn push_bytes(&mut self, bytes: &[u8]) {
+        let n = bytes.len().min(WIDTH - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&bytes[..n]);
+        self.len += n;
+    }
+}
+
+impl core::fmt::Write for LineBuf {
+    // Text past the end of the line is dropped
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.push_bytes(s.as_bytes());
+        Ok(())
     }
 }
 
@@ -69,415 +268,5861 @@ fn inb(port: u16) -> u8 {
     value
 }
 
-// --- Filesystem structures and helpers ---
+fn inw(port: u16) -> u16 {
+    let value: u16;
+    unsafe {
+        core::arch::asm!("in ax, dx", in("dx") port, out("ax") value);
+    }
+    value
+}
 
-const MAX_FILES: usize = 16;
-const MAX_DIRS: usize = 8;
-const MAX_NAME: usize = 16;
-const MAX_DATA: usize = 256;
-const MAX_DIR_STORAGE: usize = 32;
+fn outb(port: u16, value: u8) {
+    unsafe {
+        core::arch::asm!("out dx, al", in("dx") port, in("al") value);
+    }
+}
 
-#[derive(Clone, Copy)]
-struct File {
-    name: [u8; MAX_NAME],
-    data: [u8; MAX_DATA],
-    len: usize,
+// Gives a slow device time to take the last byte: a write to the unused
+// POST code port takes about a microsecond.
+fn io_wait() {
+    outb(0x80, 0);
 }
 
-#[derive(Clone, Copy)]
-struct Directory {
-    name: [u8; MAX_NAME],
-    files: [Option<File>; MAX_FILES],
-    dirs: [Option<usize>; MAX_DIRS], // indexes into DIR_STORAGE
-    parent: Option<usize>,           // index into DIR_STORAGE
+// outb followed by io_wait, for chips like the PIC that need a pause
+// between writes on real hardware
+fn outb_wait(port: u16, value: u8) {
+    outb(port, value);
+    io_wait();
 }
 
-// Pre-allocate all directories statically
-static mut DIR_STORAGE: [Directory; MAX_DIR_STORAGE] = [Directory {
-    name: [0; MAX_NAME],
-    files: [None; MAX_FILES],
-    dirs: [None; MAX_DIRS],
-    parent: None,
-}; MAX_DIR_STORAGE];
+// --- VGA text modes ---
 
-static mut DIR_ALLOC_INDEX: usize = 1; // 0 is root
+const VGA_SEQ_INDEX: u16 = 0x3C4;
+const VGA_GC_INDEX: u16 = 0x3CE;
+const VGA_CRTC_INDEX: u16 = 0x3D4;
+const VGA_ATTR_INDEX: u16 = 0x3C0;
+const VGA_INPUT_STATUS: u16 = 0x3DA;
 
-// Root dir is always at index 0
-static mut CURRENT_DIR_IDX: usize = 0;
+const GLYPHS: usize = 256;
+const GLYPH_STRIDE: usize = 32; // bytes per glyph slot in plane 2
 
-unsafe fn alloc_dir() -> Option<usize> {
-    if DIR_ALLOC_INDEX < MAX_DIR_STORAGE {
-        let idx = DIR_ALLOC_INDEX;
-        DIR_ALLOC_INDEX += 1;
-        Some(idx)
-    } else {
-        None
-    }
+// The BIOS 8x16 font, saved the first time we switch to the 8x8 font so
+// 80x25 can be restored later.
+static mut FONT_8X16: [u8; GLYPHS * 16] = [0; GLYPHS * 16];
+static mut FONT_SAVED: bool = false;
+
+// Indexed VGA registers: the data port is always index port + 1
+fn vga_write(index_port: u16, index: u8, value: u8) {
+    outb(index_port, index);
+    outb(index_port + 1, value);
 }
 
-fn name_eq(a: &[u8], b: &[u8]) -> bool {
-    let a_end = a.iter().position(|&c| c == 0 || c == b' ').unwrap_or(a.len());
-    let b_end = b.iter().position(|&c| c == 0 || c == b' ').unwrap_or(b.len());
-    a[..a_end] == b[..b_end]
+fn vga_read(index_port: u16, index: u8) -> u8 {
+    outb(index_port, index);
+    inb(index_port + 1)
 }
 
-unsafe fn find_dir(dir: &Directory, name: &[u8]) -> Option<usize> {
-    for d in dir.dirs.iter() {
-        if let Some(idx) = d {
-            let subdir = &DIR_STORAGE[*idx];
-            if name_eq(&subdir.name, name) {
-                return Some(*idx);
+// Moves the hardware cursor to a screen cell.
+fn set_cursor(row: usize, col: usize) {
+    let pos = (row * WIDTH + col) as u16;
+    vga_write(VGA_CRTC_INDEX, 0x0E, (pos >> 8) as u8);
+    vga_write(VGA_CRTC_INDEX, 0x0F, pos as u8);
+}
+
+// Whether the hardware cursor is shown (bit 5 of the cursor start register
+// hides it).
+fn cursor_shown() -> bool {
+    vga_read(VGA_CRTC_INDEX, 0x0A) & 0x20 == 0
+}
+
+fn show_cursor(on: bool) {
+    let start = vga_read(VGA_CRTC_INDEX, 0x0A) & !0x20;
+    vga_write(VGA_CRTC_INDEX, 0x0A, if on { start } else { start | 0x20 });
+}
+
+// Maps font plane 2 at the text buffer address while `f` runs. The screen
+// shows garbage in the meantime, so callers clear it afterwards.
+unsafe fn with_font_plane(f: impl FnOnce(*mut u8)) {
+    vga_write(VGA_SEQ_INDEX, 0x02, 0x04); // write plane 2 only
+    vga_write(VGA_SEQ_INDEX, 0x04, 0x07); // sequential addressing
+    vga_write(VGA_GC_INDEX, 0x04, 0x02); // read plane 2
+    vga_write(VGA_GC_INDEX, 0x05, 0x00); // no odd/even
+    vga_write(VGA_GC_INDEX, 0x06, 0x0C); // map 0xB8000, no chaining
+    f(VGA_BUFFER);
+    vga_write(VGA_SEQ_INDEX, 0x02, 0x03);
+    vga_write(VGA_SEQ_INDEX, 0x04, 0x03);
+    vga_write(VGA_GC_INDEX, 0x04, 0x00);
+    vga_write(VGA_GC_INDEX, 0x05, 0x10);
+    vga_write(VGA_GC_INDEX, 0x06, 0x0E);
+}
+
+// Sets the character cell height, which also decides the row count: the
+// display is 400 scanlines tall in both modes.
+fn set_cell_height(lines: u8) {
+    let max_scan = vga_read(VGA_CRTC_INDEX, 0x09);
+    vga_write(VGA_CRTC_INDEX, 0x09, (max_scan & 0xE0) | (lines - 1));
+    vga_write(VGA_CRTC_INDEX, 0x0A, lines - 2); // cursor start
+    vga_write(VGA_CRTC_INDEX, 0x0B, lines - 1); // cursor end
+}
+
+// Switches between 80x25 (8x16 font) and 80x50 (8x8 font). The 8x8 font is
+// made by dropping every other scanline of the BIOS font.
+fn set_text_rows(rows: usize) {
+    unsafe {
+        if rows == MAX_HEIGHT {
+            with_font_plane(|plane| {
+                for g in 0..GLYPHS {
+                    let glyph = plane.add(g * GLYPH_STRIDE);
+                    for line in 0..16 {
+                        if !FONT_SAVED {
+                            FONT_8X16[g * 16 + line] = *glyph.add(line);
+                        }
+                        if line % 2 == 0 {
+                            *glyph.add(line / 2) = FONT_8X16[g * 16 + line];
+                        }
+                    }
+                }
+                FONT_SAVED = true;
+            });
+            set_cell_height(8);
+        } else {
+            if FONT_SAVED {
+                with_font_plane(|plane| {
+                    for g in 0..GLYPHS {
+                        let glyph = plane.add(g * GLYPH_STRIDE);
+                        for line in 0..16 {
+                            *glyph.add(line) = FONT_8X16[g * 16 + line];
+                        }
+                    }
+                });
             }
+            set_cell_height(16);
         }
+        HEIGHT = rows;
     }
-    None
+    clear_screen();
 }
 
-unsafe fn find_file<'a>(dir: &'a Directory, name: &[u8]) -> Option<&'a File> {
-    for f in dir.files.iter() {
-        if let Some(file) = f {
-            if name_eq(&file.name, name) {
-                return Some(file);
-            }
-        }
+// `vmode`: reports the text mode and display state, read back from the VGA
+// registers rather than from what the kernel thinks it set.
+fn vmode(ctx: &mut ShellCtx) {
+    let cell_height = (vga_read(VGA_CRTC_INDEX, 0x09) & 0x1F) + 1;
+    let cursor_on = cursor_shown();
+    // Reading the input status register puts the attribute controller back
+    // in index mode. Bit 5 of the index keeps the display enabled.
+    inb(VGA_INPUT_STATUS);
+    outb(VGA_ATTR_INDEX, 0x20 | 0x10);
+    let blink = inb(VGA_ATTR_INDEX + 1) & 0x08 != 0;
+    inb(VGA_INPUT_STATUS);
+
+    let mut line = LineBuf::new();
+    let _ = write!(line, "Mode:      {}x{} (8x{} font)", WIDTH, height(), cell_height);
+    ctx.out(line.as_str());
+    let mut line = LineBuf::new();
+    let _ = write!(line, "Text rows: {} (bottom row is the status bar)", text_rows());
+    ctx.out(line.as_str());
+    ctx.out(if cursor_on { "Cursor:    on" } else { "Cursor:    off" });
+    ctx.out(if blink { "Blink:     on" } else { "Blink:     off (bright backgrounds)" });
+    let mut line = LineBuf::new();
+    let _ = write!(
+        line,
+        "Attribute: {:#04x} ({} on {})",
+        TEXT_ATTR,
+        COLOR_NAMES[(TEXT_ATTR & 0x0F) as usize],
+        COLOR_NAMES[(TEXT_ATTR >> 4 & 0x07) as usize]
+    );
+    ctx.out(line.as_str());
+}
+
+// --- Interrupts: IDT, PIC, PIT timer and keyboard ---
+
+const PIC1_CMD: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_CMD: u16 = 0xA0;
+const PIC2_DATA: u16 = 0xA1;
+const PIC_EOI: u8 = 0x20;
+const IRQ_BASE: usize = 32; // IRQ0..15 are remapped to vectors 32..47
+
+const PIT_HZ: u32 = 1_193_182;
+const TICKS_PER_SEC: u64 = 100;
+
+// Pushed by the CPU on every interrupt
+#[allow(dead_code)]
+#[repr(C)]
+struct InterruptStackFrame {
+    instruction_pointer: u64,
+    code_segment: u64,
+    cpu_flags: u64,
+    stack_pointer: u64,
+    stack_segment: u64,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct IdtEntry {
+    offset_low: u16,
+    selector: u16,
+    ist: u8,
+    type_attr: u8,
+    offset_mid: u16,
+    offset_high: u32,
+    reserved: u32,
+}
+
+#[repr(C, packed)]
+struct IdtPointer {
+    limit: u16,
+    base: u64,
+}
+
+static mut IDT: [IdtEntry; 256] = [IdtEntry {
+    offset_low: 0,
+    selector: 0,
+    ist: 0,
+    type_attr: 0,
+    offset_mid: 0,
+    offset_high: 0,
+    reserved: 0,
+}; 256];
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+// Scancodes pushed by the keyboard interrupt and consumed by the shell, so
+// keys typed while a command runs are not lost.
+const KEY_QUEUE_SIZE: usize = 64;
+static mut KEY_QUEUE: [u8; KEY_QUEUE_SIZE] = [0; KEY_QUEUE_SIZE];
+static KEY_HEAD: AtomicUsize = AtomicUsize::new(0); // next slot to read
+static KEY_TAIL: AtomicUsize = AtomicUsize::new(0); // next slot to write
+
+type InterruptHandler = extern "x86-interrupt" fn(InterruptStackFrame);
+
+// Exceptions that push an error code after the stack frame
+type ErrorCodeHandler = extern "x86-interrupt" fn(InterruptStackFrame, u64);
+type AbortHandler = extern "x86-interrupt" fn(InterruptStackFrame, u64) -> !;
+
+unsafe fn set_gate(vector: usize, handler: u64) {
+    let selector: u16;
+    core::arch::asm!("mov {0:x}, cs", out(reg) selector);
+    IDT[vector] = IdtEntry {
+        offset_low: handler as u16,
+        selector,
+        ist: 0,
+        type_attr: 0x8E, // present, ring 0, interrupt gate
+        offset_mid: (handler >> 16) as u16,
+        offset_high: (handler >> 32) as u32,
+        reserved: 0,
+    };
+}
+
+fn init_interrupts() {
+    unsafe {
+        set_gate(DOUBLE_FAULT, double_fault_handler as AbortHandler as usize as u64);
+        set_gate(PAGE_FAULT, page_fault_handler as ErrorCodeHandler as usize as u64);
+        set_gate(IRQ_BASE, timer_handler as InterruptHandler as usize as u64);
+        set_gate(IRQ_BASE + 1, keyboard_handler as InterruptHandler as usize as u64);
+        set_gate(IRQ_BASE + 4, serial_handler as InterruptHandler as usize as u64);
+        let idt_ptr = IdtPointer {
+            limit: (core::mem::size_of::<[IdtEntry; 256]>() - 1) as u16,
+            base: core::ptr::addr_of!(IDT) as u64,
+        };
+        core::arch::asm!("lidt [{}]", in(reg) &idt_ptr);
     }
-    None
+
+    // Remap the PICs so IRQs don't collide with CPU exceptions
+    outb_wait(PIC1_CMD, 0x11);
+    outb_wait(PIC2_CMD, 0x11);
+    outb_wait(PIC1_DATA, IRQ_BASE as u8);
+    outb_wait(PIC2_DATA, IRQ_BASE as u8 + 8);
+    outb_wait(PIC1_DATA, 4);
+    outb_wait(PIC2_DATA, 2);
+    outb_wait(PIC1_DATA, 0x01);
+    outb_wait(PIC2_DATA, 0x01);
+    // Only the timer (IRQ0), keyboard (IRQ1) and COM1 (IRQ4) are unmasked
+    outb(PIC1_DATA, 0xEC);
+    outb(PIC2_DATA, 0xFF);
+
+    // PIT channel 0, square wave at TICKS_PER_SEC
+    let divisor = PIT_HZ / TICKS_PER_SEC as u32;
+    outb(0x43, 0x36);
+    outb(0x40, divisor as u8);
+    outb(0x40, (divisor >> 8) as u8);
+
+    unsafe { core::arch::asm!("sti"); }
 }
 
-unsafe fn find_file_mut<'a>(dir: &'a mut Directory, name: &[u8]) -> Option<&'a mut File> {
-    for f in dir.files.iter_mut() {
-        if let Some(file) = f {
-            if name_eq(&file.name, name) {
-                return Some(file);
-            }
-        }
+extern "x86-interrupt" fn timer_handler(_frame: InterruptStackFrame) {
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    let deadline = DEADLINE.load(Ordering::Relaxed);
+    if deadline != 0 && now >= deadline {
+        DEADLINE_PASSED.store(true, Ordering::Relaxed);
     }
-    None
+    outb(PIC1_CMD, PIC_EOI);
 }
 
-// --- Main entry point ---
+extern "x86-interrupt" fn keyboard_handler(_frame: InterruptStackFrame) {
+    push_scancode(inb(0x60));
+    outb(PIC1_CMD, PIC_EOI);
+}
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+// Only the keyboard interrupt (and tests) add to the queue
+fn push_scancode(scancode: u8) {
+    let tail = KEY_TAIL.load(Ordering::Relaxed);
+    let next = (tail + 1) % KEY_QUEUE_SIZE;
+    // Drop the key if the queue is full
+    if next != KEY_HEAD.load(Ordering::Acquire) {
+        unsafe { KEY_QUEUE[tail] = scancode; }
+        KEY_TAIL.store(next, Ordering::Release);
+    }
+}
+
+// --- Serial port (COM1) ---
+
+const COM1: u16 = 0x3F8;
+
+// Bytes received on COM1, pushed by its interrupt so a fast paste isn't lost
+// while the shell is busy running a command.
+const SERIAL_QUEUE_SIZE: usize = 256;
+static mut SERIAL_QUEUE: [u8; SERIAL_QUEUE_SIZE] = [0; SERIAL_QUEUE_SIZE];
+static SERIAL_HEAD: AtomicUsize = AtomicUsize::new(0); // next slot to read
+static SERIAL_TAIL: AtomicUsize = AtomicUsize::new(0); // next slot to write
+
+// Scancodes still to be returned for the last serial byte, in reverse order.
+// Only the shell side touches these.
+static mut SERIAL_PENDING: [u8; 4] = [0; 4];
+static mut SERIAL_PENDING_LEN: usize = 0;
+// the last byte was a CR, so an LF right after it is the same line break
+static mut SERIAL_AFTER_CR: bool = false;
+
+fn init_serial() {
+    outb(COM1 + 1, 0x00); // no interrupts while configuring
+    outb(COM1 + 3, 0x80); // DLAB on: the next two ports set the divisor
+    outb(COM1, 0x01); // 115200 baud
+    outb(COM1 + 1, 0x00);
+    outb(COM1 + 3, 0x03); // 8 data bits, no parity, 1 stop bit
+    outb(COM1 + 2, 0xC7); // enable and clear the FIFOs, 14-byte threshold
+    outb(COM1 + 4, 0x0B); // DTR, RTS and OUT2, which gates the IRQ line
+    outb(COM1 + 1, 0x01); // interrupt when data arrives
+}
+
+fn serial_write_byte(byte: u8) {
+    // wait for the transmit holding register to empty
+    while inb(COM1 + 5) & 0x20 == 0 {}
+    outb(COM1, byte);
+}
+
+fn serial_write_str(s: &str) {
+    for byte in s.bytes() {
+        serial_write_byte(byte);
+    }
+}
+
+// Copies a line shown on screen to COM1, so `qemu -serial stdio` keeps a
+// transcript of the session.
+fn serial_mirror(line: &[u8]) {
+    for &byte in line {
+        serial_write_byte(byte);
+    }
+    serial_write_str("\r\n");
+}
+
+extern "x86-interrupt" fn serial_handler(_frame: InterruptStackFrame) {
+    // drain the whole FIFO; one interrupt may stand for many bytes
+    while inb(COM1 + 5) & 0x01 != 0 {
+        push_serial(inb(COM1));
+    }
+    outb(PIC1_CMD, PIC_EOI);
+}
+
+// Only the COM1 interrupt (and tests) add to the queue
+fn push_serial(byte: u8) {
+    let tail = SERIAL_TAIL.load(Ordering::Relaxed);
+    let next = (tail + 1) % SERIAL_QUEUE_SIZE;
+    if next != SERIAL_HEAD.load(Ordering::Acquire) {
+        unsafe { SERIAL_QUEUE[tail] = byte; }
+        SERIAL_TAIL.store(next, Ordering::Release);
+    }
+}
+
+fn pop_serial() -> Option<u8> {
+    let head = SERIAL_HEAD.load(Ordering::Relaxed);
+    if head == SERIAL_TAIL.load(Ordering::Acquire) {
+        return None;
+    }
+    let byte = unsafe { SERIAL_QUEUE[head] };
+    SERIAL_HEAD.store((head + 1) % SERIAL_QUEUE_SIZE, Ordering::Release);
+    Some(byte)
+}
+
+// Make code and shift state that type `c` on the keyboard layout.
+fn ascii_to_scancode(c: u8) -> Option<(u8, bool)> {
+    (1..0x3A).find_map(|scancode| {
+        if scancode_to_ascii(scancode, false) == Some(c) {
+            Some((scancode, false))
+        } else if scancode_to_ascii(scancode, true) == Some(c) {
+            Some((scancode, true))
+        } else {
+            None
+        }
+    })
+}
+
+// Turns received serial bytes into the scancodes of the keys that would type
+// them, so everything reading the keyboard also takes serial input. CR, LF
+// and CR LF all become one Enter.
+fn serial_scancode() -> Option<u8> {
     unsafe {
-        // Initialize root directory
-        DIR_STORAGE[0].name = *b"/               ";
-        DIR_STORAGE[0].files = [None; MAX_FILES];
-        DIR_STORAGE[0].dirs = [None; MAX_DIRS];
-        DIR_STORAGE[0].parent = None;
-        CURRENT_DIR_IDX = 0;
+        while SERIAL_PENDING_LEN == 0 {
+            let byte = pop_serial()?;
+            let after_cr = SERIAL_AFTER_CR;
+            SERIAL_AFTER_CR = byte == b'\r';
+            let (scancode, shift) = match byte {
+                b'\n' if after_cr => continue,
+                b'\r' | b'\n' => (0x1C, false),
+                0x08 | 0x7F => (0x0E, false), // backspace or delete
+                0x1B => (0x01, false),         // escape
+                _ => match ascii_to_scancode(byte) {
+                    Some(key) => key,
+                    None => continue,
+                },
+            };
+            // a letter should arrive as sent, whatever Caps Lock is set to
+            let shift = shift ^ (byte.is_ascii_alphabetic() && CAPS_LOCK.load(Ordering::Relaxed));
+            // stored backwards: shift down, key down, key up, shift up
+            let codes: &[u8] = if shift {
+                &[0xAA, scancode | 0x80, scancode, 0x2A]
+            } else {
+                &[scancode | 0x80, scancode]
+            };
+            SERIAL_PENDING[..codes.len()].copy_from_slice(codes);
+            SERIAL_PENDING_LEN = codes.len();
+        }
+        SERIAL_PENDING_LEN -= 1;
+        Some(SERIAL_PENDING[SERIAL_PENDING_LEN])
     }
+}
 
-    print_boot_logo();
-    for _ in 0..5_000_000 { unsafe { core::arch::asm!("nop"); } }
+// --- CPU exceptions ---
 
-    clear_screen();
-    print_at("OxOS Command Line", 0);
+const DOUBLE_FAULT: usize = 8;
+const PAGE_FAULT: usize = 14;
 
-    let mut row = 7;
-    let mut col;
-    let mut prompt_len;
-    let mut path_buf = [0u8; 64];
+// Stack pointer in _start just before it entered the shell loop
+static mut SHELL_STACK: u64 = 0;
 
-    // Print initial prompt
-    let prompt = build_path(unsafe { CURRENT_DIR_IDX }, &mut path_buf);
-    print_at(prompt, row);
-    prompt_len = prompt.len();
-    col = prompt_len;
+// Armed while a command touches memory that may not be mapped (memtest). A
+// page fault in that window aborts the command and restarts the shell loop;
+// any other fault is a kernel bug and halts the machine.
+static FAULT_RECOVERY: AtomicBool = AtomicBool::new(false);
+// Shell row the armed command has printed up to, and the faulting address
+static FAULT_ROW: AtomicUsize = AtomicUsize::new(0);
+static FAULT_ADDR: AtomicU64 = AtomicU64::new(0);
+
+// Call again whenever the command prints, so the report lands below its output.
+fn arm_fault_recovery(row: usize) {
+    FAULT_ROW.store(row, Ordering::Relaxed);
+    FAULT_RECOVERY.store(true, Ordering::Relaxed);
+}
+
+fn disarm_fault_recovery() {
+    FAULT_RECOVERY.store(false, Ordering::Relaxed);
+}
+
+extern "x86-interrupt" fn page_fault_handler(mut frame: InterruptStackFrame, error_code: u64) {
+    let addr: u64;
+    unsafe { core::arch::asm!("mov {}, cr2", out(reg) addr); }
+    // only the shell task runs armed commands
+    if !FAULT_RECOVERY.swap(false, Ordering::Relaxed) || unsafe { CURRENT_TASK } != 0 {
+        fault_halt("Page fault", addr, frame.instruction_pointer, error_code);
+    }
+    FAULT_ADDR.store(addr, Ordering::Relaxed);
+
+    // Return into recover_shell instead of the faulting instruction, with the
+    // stack unwound to where _start called the shell. The extra slot stands
+    // in for a return address so the entry is ABI-aligned.
+    let stack = (unsafe { SHELL_STACK } & !0xF) - 8;
+    let entry = recover_shell as extern "C" fn() -> ! as usize as u64;
+    unsafe {
+        core::ptr::write_volatile(&mut frame.instruction_pointer, entry);
+        core::ptr::write_volatile(&mut frame.stack_pointer, stack);
+    }
+}
 
-    let mut last_scancode = 0u8;
-    let mut cmd_buf = [0u8; 80];
-    let mut cmd_len = 0;
-    let mut shift = false;
-    let mut blink_counter = 0u32;
+extern "x86-interrupt" fn double_fault_handler(frame: InterruptStackFrame, error_code: u64) -> ! {
+    fault_halt("Double fault", 0, frame.instruction_pointer, error_code);
+}
 
+// Reports an unrecoverable fault on the last text row and stops the CPU.
+// Writes the VGA buffer directly: the fault may have hit with VGA_LOCK held.
+fn fault_halt(what: &str, addr: u64, ip: u64, error_code: u64) -> ! {
+    let mut line = LineBuf::new();
+    let _ = write!(line, "{} at {:#x} (rip {:#x}, error {:#x}), halted", what, addr, ip, error_code);
+    let row = text_rows() - 1;
+    for col in 0..WIDTH {
+        let c = line.as_str().as_bytes().get(col).copied().unwrap_or(b' ');
+        unsafe {
+            *VGA_BUFFER.add((row * WIDTH + col) * 2) = c;
+            *VGA_BUFFER.add((row * WIDTH + col) * 2 + 1) = attr(Color::White, Color::Red);
+        }
+    }
     loop {
-        let scancode = inb(0x60);
+        unsafe { core::arch::asm!("cli", "hlt"); }
+    }
+}
 
-        // Shift press/release handling
-        match scancode {
-            0x2A | 0x36 => { shift = true; }
-            0xAA | 0xB6 => { shift = false; }
-            _ => {}
+// Entered from page_fault_handler in place of the faulting command. Whatever
+// the command had on the stack is gone, so the shell starts over below its
+// last line of output.
+extern "C" fn recover_shell() -> ! {
+    stop_watchdog();
+    let mut ctx = ShellCtx { row: FAULT_ROW.load(Ordering::Relaxed), sink: None };
+    let mut line = LineBuf::new();
+    let _ = write!(line, "Page fault at {:#x}, command aborted", FAULT_ADDR.load(Ordering::Relaxed));
+    ctx.err(line.as_str());
+    ctx.make_room(1);
+    shell(ctx)
+}
+
+fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+// --- Command watchdog ---
+
+// With TIMEOUT=n set, a command still running n ticks after it started is
+// abandoned. The timer only raises a flag; long-running loops poll it through
+// timed_out and give up, so nothing is cut off mid-update.
+static DEADLINE: AtomicU64 = AtomicU64::new(0); // 0 when not watching
+static DEADLINE_PASSED: AtomicBool = AtomicBool::new(false);
+// a command gave up because of the deadline
+static TIMED_OUT: AtomicBool = AtomicBool::new(false);
+
+fn start_watchdog() {
+    let timeout = var_get(b"TIMEOUT").and_then(parse_u32).unwrap_or(0);
+    DEADLINE_PASSED.store(false, Ordering::Relaxed);
+    TIMED_OUT.store(false, Ordering::Relaxed);
+    DEADLINE.store(if timeout == 0 { 0 } else { ticks() + timeout as u64 }, Ordering::Relaxed);
+}
+
+// Stops watching and returns whether the command was cut short.
+fn stop_watchdog() -> bool {
+    DEADLINE.store(0, Ordering::Relaxed);
+    DEADLINE_PASSED.store(false, Ordering::Relaxed);
+    TIMED_OUT.swap(false, Ordering::Relaxed)
+}
+
+// True once the shell's command has run out of time. Background tasks are
+// never watched.
+fn timed_out() -> bool {
+    if unsafe { CURRENT_TASK } != 0 || !DEADLINE_PASSED.load(Ordering::Relaxed) {
+        return false;
+    }
+    TIMED_OUT.store(true, Ordering::Relaxed);
+    true
+}
+
+// Whether Shift and Ctrl are down, as of the last scancode handed out. Input
+// loops read these instead of keeping their own copy, so a release taken by
+// one loop (say `read`) is seen by the shell and the editor too.
+static SHIFT_HELD: AtomicBool = AtomicBool::new(false);
+static CTRL_HELD: AtomicBool = AtomicBool::new(false);
+
+// The last scancode handed out was the 0xE0 prefix of a grey key
+static KEY_EXTENDED: AtomicBool = AtomicBool::new(false);
+
+// Lock key state, flipped on each press wherever the key is read so the
+// shell, the editor and read_line all agree
+static CAPS_LOCK: AtomicBool = AtomicBool::new(false);
+static NUM_LOCK: AtomicBool = AtomicBool::new(false);
+
+// Whether the lock keys are held down. Auto-repeat sends the make code again
+// while a key is held, so only the first one flips the lock.
+static CAPS_DOWN: AtomicBool = AtomicBool::new(false);
+static NUM_DOWN: AtomicBool = AtomicBool::new(false);
+
+// Next queued scancode (make or break), if any. Typed keys come first, then
+// bytes received on the serial port.
+fn pop_scancode() -> Option<u8> {
+    let head = KEY_HEAD.load(Ordering::Relaxed);
+    let scancode = if head == KEY_TAIL.load(Ordering::Acquire) {
+        serial_scancode()?
+    } else {
+        let scancode = unsafe { KEY_QUEUE[head] };
+        KEY_HEAD.store((head + 1) % KEY_QUEUE_SIZE, Ordering::Release);
+        scancode
+    };
+    // E0 2A and E0 AA are fake shifts some grey keys send around their own
+    // code; they're ignored
+    let extended = KEY_EXTENDED.swap(scancode == 0xE0, Ordering::Relaxed);
+    match scancode {
+        0x2A | 0x36 if !extended => SHIFT_HELD.store(true, Ordering::Relaxed),
+        0xAA | 0xB6 if !extended => SHIFT_HELD.store(false, Ordering::Relaxed),
+        0x1D => CTRL_HELD.store(true, Ordering::Relaxed),
+        0x9D => CTRL_HELD.store(false, Ordering::Relaxed),
+        0x3A if !CAPS_DOWN.swap(true, Ordering::Relaxed) => {
+            CAPS_LOCK.fetch_xor(true, Ordering::Relaxed);
+            set_keyboard_leds();
+        }
+        0xBA => CAPS_DOWN.store(false, Ordering::Relaxed),
+        0x45 if !NUM_DOWN.swap(true, Ordering::Relaxed) => {
+            NUM_LOCK.fetch_xor(true, Ordering::Relaxed);
+            set_keyboard_leds();
+        }
+        0xC5 => NUM_DOWN.store(false, Ordering::Relaxed),
+        _ => {}
+    }
+    Some(scancode)
+}
+
+// Lights the keyboard's Caps Lock and Num Lock LEDs to match. The keyboard
+// acknowledges each byte with 0xFA, which input loops ignore like any other
+// break code.
+fn set_keyboard_leds() {
+    let num = NUM_LOCK.load(Ordering::Relaxed) as u8;
+    let caps = CAPS_LOCK.load(Ordering::Relaxed) as u8;
+    for byte in [0xED, caps << 2 | num << 1] {
+        // wait for the controller to take the previous byte, but not forever
+        // on machines without a PS/2 controller
+        for _ in 0..10_000 {
+            if inb(0x64) & 0x02 == 0 {
+                break;
+            }
+        }
+        outb(0x60, byte);
+    }
+}
+
+// The character a key types with the current modifiers: Caps Lock swaps the
+// case of letters and leaves every other key to Shift alone.
+fn typed_char(scancode: u8, shift: bool) -> Option<u8> {
+    let c = scancode_to_ascii(scancode, shift)?;
+    if c.is_ascii_alphabetic() && CAPS_LOCK.load(Ordering::Relaxed) {
+        Some(c ^ 0x20)
+    } else {
+        Some(c)
+    }
+}
+
+// Sleeps until the next interrupt.
+fn halt() {
+    unsafe { core::arch::asm!("hlt"); }
+}
+
+// Blocks until a scancode (make or break) is available.
+fn read_scancode() -> u8 {
+    loop {
+        if let Some(scancode) = pop_scancode() {
+            return scancode;
+        }
+        yield_now();
+        halt();
+    }
+}
+
+// Waits for the given number of timer ticks, letting other tasks run. Keys
+// pressed meanwhile stay queued for the shell. Ends early on a timeout.
+fn sleep_ticks(n: u64) {
+    let start = ticks();
+    while ticks() - start < n && !timed_out() {
+        yield_now();
+        halt();
+    }
+}
+
+// --- ATA disk (primary bus, PIO) ---
+
+const ATA_DATA: u16 = 0x1F0;
+const ATA_SECTOR_COUNT: u16 = 0x1F2;
+const ATA_LBA_LOW: u16 = 0x1F3;
+const ATA_LBA_MID: u16 = 0x1F4;
+const ATA_LBA_HIGH: u16 = 0x1F5;
+const ATA_DRIVE: u16 = 0x1F6;
+const ATA_STATUS: u16 = 0x1F7; // reads status, writes commands
+
+const ATA_CMD_IDENTIFY: u8 = 0xEC;
+const ATA_SR_ERR: u8 = 0x01;
+const ATA_SR_DRQ: u8 = 0x08;
+const ATA_SR_BSY: u8 = 0x80;
+
+// Poll iterations before giving up on a drive that never answers
+const ATA_TIMEOUT: usize = 1_000_000;
+
+enum AtaError {
+    NoDisk,
+    NotAta, // e.g. an ATAPI CD-ROM
+    Timeout,
+    DeviceError,
+}
+
+// Issues IDENTIFY to the primary master and returns its 256-word answer.
+fn ata_identify() -> Result<[u16; 256], AtaError> {
+    outb(ATA_DRIVE, 0xA0);
+    // A floating bus reads as 0xFF: nothing is attached
+    if inb(ATA_STATUS) == 0xFF {
+        return Err(AtaError::NoDisk);
+    }
+    outb(ATA_SECTOR_COUNT, 0);
+    outb(ATA_LBA_LOW, 0);
+    outb(ATA_LBA_MID, 0);
+    outb(ATA_LBA_HIGH, 0);
+    outb(ATA_STATUS, ATA_CMD_IDENTIFY);
+    if inb(ATA_STATUS) == 0 {
+        return Err(AtaError::NoDisk);
+    }
+
+    let mut spins = 0;
+    while inb(ATA_STATUS) & ATA_SR_BSY != 0 {
+        spins += 1;
+        if spins > ATA_TIMEOUT {
+            return Err(AtaError::Timeout);
+        }
+    }
+    if inb(ATA_LBA_MID) != 0 || inb(ATA_LBA_HIGH) != 0 {
+        return Err(AtaError::NotAta);
+    }
+    loop {
+        let status = inb(ATA_STATUS);
+        if status & ATA_SR_ERR != 0 {
+            return Err(AtaError::DeviceError);
+        }
+        if status & ATA_SR_DRQ != 0 {
+            break;
+        }
+        spins += 1;
+        if spins > ATA_TIMEOUT {
+            return Err(AtaError::Timeout);
+        }
+    }
+
+    let mut words = [0u16; 256];
+    for word in words.iter_mut() {
+        *word = inw(ATA_DATA);
+    }
+    Ok(words)
+}
+
+// IDENTIFY strings store two characters per word, high byte first, padded
+// with spaces.
+fn ata_string(words: &[u16], out: &mut [u8]) -> usize {
+    let mut len = 0;
+    for &word in words {
+        for c in [(word >> 8) as u8, word as u8] {
+            if len < out.len() {
+                out[len] = c;
+                len += 1;
+            }
+        }
+    }
+    while len > 0 && out[len - 1] == b' ' {
+        len -= 1;
+    }
+    len
+}
+
+fn diskinfo(ctx: &mut ShellCtx) {
+    let words = match ata_identify() {
+        Ok(words) => words,
+        Err(AtaError::NoDisk) => return ctx.err("No disk on the primary ATA bus"),
+        Err(AtaError::NotAta) => return ctx.err("Primary drive is not an ATA disk"),
+        Err(AtaError::Timeout) => return ctx.err("Disk did not respond"),
+        Err(AtaError::DeviceError) => return ctx.err("Disk reported an error"),
+    };
+
+    let mut text = [0u8; 40];
+    let len = ata_string(&words[27..47], &mut text);
+    let mut line = LineBuf::new();
+    line.push_bytes(b"Model:   ");
+    line.push_bytes(&text[..len]);
+    ctx.out_bytes(line.as_bytes());
+
+    let len = ata_string(&words[10..20], &mut text);
+    let mut line = LineBuf::new();
+    line.push_bytes(b"Serial:  ");
+    line.push_bytes(text[..len].trim_ascii_start());
+    ctx.out_bytes(line.as_bytes());
+
+    // Words 100-103 hold the LBA48 count when bit 10 of word 83 is set,
+    // otherwise words 60-61 hold the LBA28 count.
+    let sectors = if words[83] & (1 << 10) != 0 {
+        words[100] as u64 | (words[101] as u64) << 16 | (words[102] as u64) << 32 | (words[103] as u64) << 48
+    } else {
+        words[60] as u64 | (words[61] as u64) << 16
+    };
+    let mut line = LineBuf::new();
+    let _ = write!(line, "Sectors: {} ({} KiB)", sectors, sectors / 2);
+    ctx.out(line.as_str());
+}
+
+// --- Real-time clock (CMOS) ---
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+fn cmos_read(reg: u8) -> u8 {
+    outb(CMOS_INDEX, reg);
+    inb(CMOS_DATA)
+}
+
+// Current wall-clock time as (hours, minutes, seconds), 24-hour.
+fn rtc_time() -> (u8, u8, u8) {
+    // wait out an update in progress so the fields are consistent
+    while cmos_read(0x0A) & 0x80 != 0 {}
+    let (mut h, mut m, mut s) = (cmos_read(0x04), cmos_read(0x02), cmos_read(0x00));
+    let status_b = cmos_read(0x0B);
+    let pm = h & 0x80 != 0;
+    h &= 0x7F;
+    // bit 2 set: binary values, otherwise BCD
+    if status_b & 0x04 == 0 {
+        let from_bcd = |v: u8| (v >> 4) * 10 + (v & 0x0F);
+        h = from_bcd(h);
+        m = from_bcd(m);
+        s = from_bcd(s);
+    }
+    // bit 1 clear: 12-hour clock
+    if status_b & 0x02 == 0 {
+        h = match (h, pm) {
+            (12, false) => 0,
+            (12, true) => 12,
+            (h, true) => h + 12,
+            (h, false) => h,
+        };
+    }
+    (h, m, s)
+}
+
+// Current date as (year, month, day). The CMOS keeps only a two-digit year,
+// taken to be in the 2000s.
+fn rtc_date() -> (u16, u8, u8) {
+    while cmos_read(0x0A) & 0x80 != 0 {}
+    let (mut y, mut mo, mut d) = (cmos_read(0x09), cmos_read(0x08), cmos_read(0x07));
+    if cmos_read(0x0B) & 0x04 == 0 {
+        let from_bcd = |v: u8| (v >> 4) * 10 + (v & 0x0F);
+        y = from_bcd(y);
+        mo = from_bcd(mo);
+        d = from_bcd(d);
+    }
+    (2000 + y as u16, mo, d)
+}
+
+// --- Memory test ---
+
+// Regions memtest must never touch: the low megabyte (BIOS data, the VGA
+// buffer and the bootloader's structures) and the kernel image itself.
+const LOW_MEMORY_END: usize = 0x10_0000;
+const MEMTEST_MAX_REPORTS: usize = 5;
+// The bootloader's default kernel stack is 80 pages. It lies outside the
+// loaded image, so memtest guards this much either side of the stack pointer.
+const KERNEL_STACK_SIZE: usize = 80 * 4096;
+
+extern "C" {
+    // Provided by the linker: the first byte of the loaded image and the end
+    // of .bss.
+    static __ehdr_start: u8;
+    static _end: u8;
+}
+
+fn kernel_range() -> (usize, usize) {
+    (core::ptr::addr_of!(__ehdr_start) as usize, core::ptr::addr_of!(_end) as usize)
+}
+
+// Addresses that may hold the running stack: wherever rsp is within it, the
+// rest is no more than a stack's size away.
+fn stack_range() -> (usize, usize) {
+    let rsp: usize;
+    unsafe { core::arch::asm!("mov {}, rsp", out(reg) rsp); }
+    (rsp.saturating_sub(KERNEL_STACK_SIZE), rsp.saturating_add(KERNEL_STACK_SIZE))
+}
+
+// `memtest <start> <len>`: writes walking-ones and walking-zeros byte patterns
+// over the region, reads each back and reports mismatching addresses. The
+// region's previous contents are destroyed. Touching unmapped memory aborts
+// the command with a page fault report.
+fn memtest(ctx: &mut ShellCtx, args: &[u8]) {
+    let usage = "Usage: memtest <start> <len> (hex)";
+    let mut args = args.split(|&c| c == b' ').filter(|a| !a.is_empty());
+    let (start, len) = match (args.next().and_then(parse_hex), args.next().and_then(parse_hex), args.next()) {
+        (Some(start), Some(len), None) if len > 0 => (start as usize, len as usize),
+        _ => return ctx.err(usage),
+    };
+    let end = match start.checked_add(len) {
+        Some(end) => end,
+        None => return ctx.err("Range wraps around the address space"),
+    };
+    let (kernel_start, kernel_end) = kernel_range();
+    if start < LOW_MEMORY_END || (start < kernel_end && end > kernel_start) {
+        return ctx.err("Range overlaps the kernel or VGA memory");
+    }
+    let (stack_start, stack_end) = stack_range();
+    if start < stack_end && end > stack_start {
+        return ctx.err("Range overlaps the kernel stack");
+    }
+
+    // an unmapped address in the range aborts the command instead of halting
+    arm_fault_recovery(ctx.row);
+    let mut errors = 0usize;
+    for bit in 0..16 {
+        if timed_out() {
+            break;
+        }
+        // walking ones first, then walking zeros
+        let pattern = if bit < 8 { 1u8 << bit } else { !(1u8 << (bit - 8)) };
+        for addr in start..end {
+            unsafe { core::ptr::write_volatile(addr as *mut u8, pattern) };
+        }
+        for addr in start..end {
+            let got = unsafe { core::ptr::read_volatile(addr as *const u8) };
+            if got != pattern {
+                if errors < MEMTEST_MAX_REPORTS {
+                    let mut line = LineBuf::new();
+                    let _ = write!(line, "Mismatch at {:#x}: wrote {:#04x}, read {:#04x}", addr, pattern, got);
+                    ctx.err(line.as_str());
+                    arm_fault_recovery(ctx.row);
+                }
+                errors += 1;
+            }
+        }
+    }
+    disarm_fault_recovery();
+    // an unfinished run proves nothing either way
+    if timed_out() {
+        return;
+    }
+
+    let mut line = LineBuf::new();
+    if errors == 0 {
+        let _ = write!(line, "memtest: {:#x} bytes OK", len);
+        ctx.out(line.as_str());
+    } else {
+        let _ = write!(line, "memtest: {} mismatches", errors);
+        ctx.err(line.as_str());
+    }
+}
+
+// --- Benchmarks ---
+
+// Each benchmark runs for this many timer ticks
+const BENCH_TICKS: u64 = TICKS_PER_SEC / 4;
+const BENCH_BYTES: usize = 1024;
+
+// Calls `op` repeatedly for BENCH_TICKS and returns (iterations, ns per call).
+// The PIT only counts 10 ms steps, so the figures are approximate.
+fn bench_op(mut op: impl FnMut()) -> (u64, u64) {
+    // start on a tick boundary so the whole window is measured
+    let start = ticks();
+    while ticks() == start {
+        halt();
+    }
+    let start = ticks();
+    let mut iterations = 0u64;
+    while ticks() - start < BENCH_TICKS {
+        op();
+        iterations += 1;
+    }
+    let ns = (ticks() - start) * (1_000_000_000 / TICKS_PER_SEC);
+    (iterations, ns / iterations.max(1))
+}
+
+// `bench`: times memcpy, memset, name_eq and build_path
+fn bench(ctx: &mut ShellCtx) {
+    let mut src = [0x5au8; BENCH_BYTES];
+    let mut dst = [0u8; BENCH_BYTES];
+    let mut path = [0u8; 64];
+    ctx.out("operation           calls     ns/call");
+    let results = [
+        ("memcpy 1 KiB", bench_op(|| unsafe {
+            memcpy(core::hint::black_box(dst.as_mut_ptr()), src.as_ptr(), BENCH_BYTES);
+        })),
+        ("memset 1 KiB", bench_op(|| unsafe {
+            memset(core::hint::black_box(src.as_mut_ptr()), 0x5a, BENCH_BYTES);
+        })),
+        ("name_eq", bench_op(|| {
+            core::hint::black_box(name_eq(core::hint::black_box(b"notes.txt\0\0\0\0\0\0\0"), b"notes.txt"));
+        })),
+        ("build_path", bench_op(|| {
+            core::hint::black_box(build_path(unsafe { CURRENT_DIR_IDX }, &mut path));
+        })),
+    ];
+    for (name, (calls, ns)) in results {
+        let mut line = LineBuf::new();
+        let _ = write!(line, "{:<16}{:>9}{:>12}", name, calls, ns);
+        ctx.out(line.as_str());
+    }
+}
+
+// --- Tasks ---
+
+// Cooperative multitasking: every task runs until it calls yield_now(), which
+// saves its registers on its own stack and resumes the next ready task. Task 0
+// is the shell, running on the boot stack.
+const MAX_TASKS: usize = 4;
+const TASK_STACK_SIZE: usize = 16 * 1024;
+
+#[derive(Clone, Copy, PartialEq)]
+enum TaskState {
+    Running,
+    Ready,
+    Finished,
+}
+
+#[derive(Clone, Copy)]
+struct Task {
+    pid: usize,
+    name: &'static str,
+    state: TaskState,
+    started: u64,       // tick the task was created at
+    stack_pointer: u64, // saved rsp while the task is not running
+    entry: fn(),
+}
+
+#[repr(align(16))]
+struct TaskStack([u8; TASK_STACK_SIZE]);
+
+static mut TASKS: [Option<Task>; MAX_TASKS] = [None; MAX_TASKS];
+static mut TASK_STACKS: [TaskStack; MAX_TASKS] = [const { TaskStack([0; TASK_STACK_SIZE]) }; MAX_TASKS];
+static mut CURRENT_TASK: usize = 0;
+
+// Registers the code that is already running (the shell) as task 0.
+unsafe fn init_tasks() {
+    TASKS[0] = Some(Task {
+        pid: 0,
+        name: "shell",
+        state: TaskState::Running,
+        started: ticks(),
+        stack_pointer: 0,
+        entry: || {},
+    });
+    CURRENT_TASK = 0;
+}
+
+// Creates a task that starts running `entry` at its first turn. Slots of
+// finished tasks are reused.
+unsafe fn spawn_task(name: &'static str, entry: fn()) -> Option<usize> {
+    let tasks = &mut *core::ptr::addr_of_mut!(TASKS);
+    let slot = tasks
+        .iter()
+        .position(|t| t.is_none() || matches!(t, Some(task) if task.state == TaskState::Finished))?;
+
+    // Initial frame popped by switch_stack: six callee-saved registers, then
+    // the return address. The extra slot keeps the entry ABI-aligned.
+    let stack = core::ptr::addr_of_mut!(TASK_STACKS[slot].0) as *mut u8;
+    let top = stack.add(TASK_STACK_SIZE) as *mut u64;
+    let frame = top.sub(8);
+    for i in 0..6 {
+        *frame.add(i) = 0;
+    }
+    *frame.add(6) = task_trampoline as extern "C" fn() -> ! as usize as u64;
+    *frame.add(7) = 0;
+
+    tasks[slot] = Some(Task {
+        pid: slot,
+        name,
+        state: TaskState::Ready,
+        started: ticks(),
+        stack_pointer: frame as u64,
+        entry,
+    });
+    Some(slot)
+}
+
+// First code a new task runs: calls its entry, then retires the task.
+extern "C" fn task_trampoline() -> ! {
+    let entry = unsafe { TASKS[CURRENT_TASK].map(|t| t.entry) };
+    if let Some(entry) = entry {
+        entry();
+    }
+    unsafe {
+        if let Some(task) = TASKS[CURRENT_TASK].as_mut() {
+            task.state = TaskState::Finished;
+        }
+    }
+    loop {
+        yield_now();
+    }
+}
+
+// Saves the callee-saved registers and stack pointer of the current task into
+// `*old_sp` and resumes the task whose stack pointer is `new_sp`.
+#[unsafe(naked)]
+unsafe extern "C" fn switch_stack(old_sp: *mut u64, new_sp: u64) {
+    core::arch::naked_asm!(
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov [rdi], rsp",
+        "mov rsp, rsi",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+    )
+}
+
+// Hands the CPU to the next ready task, round robin. Returns immediately if
+// no other task is ready.
+fn yield_now() {
+    unsafe {
+        let current = CURRENT_TASK;
+        let next = (1..=MAX_TASKS)
+            .map(|i| (current + i) % MAX_TASKS)
+            .find(|&i| matches!(TASKS[i], Some(task) if task.state == TaskState::Ready));
+        let next = match next {
+            Some(next) if next != current => next,
+            _ => return,
+        };
+        if let Some(task) = TASKS[current].as_mut() {
+            if task.state == TaskState::Running {
+                task.state = TaskState::Ready;
+            }
+        }
+        let new_sp = match TASKS[next].as_mut() {
+            Some(task) => {
+                task.state = TaskState::Running;
+                task.stack_pointer
+            }
+            None => return,
+        };
+        CURRENT_TASK = next;
+        let old_sp = match TASKS[current].as_mut() {
+            Some(task) => core::ptr::addr_of_mut!(task.stack_pointer),
+            None => return,
+        };
+        switch_stack(old_sp, new_sp);
+    }
+}
+
+// Stops a background task; it is never scheduled again.
+unsafe fn kill_task(pid: usize) -> bool {
+    if pid == 0 || pid >= MAX_TASKS {
+        return false;
+    }
+    match TASKS[pid].as_mut() {
+        Some(task) if task.state != TaskState::Finished => {
+            task.state = TaskState::Finished;
+            true
+        }
+        _ => false,
+    }
+}
+
+// Background jobs that `spawn` can start. They draw in the header row so they
+// don't disturb the shell's output.
+const BACKGROUND_JOBS: [(&str, fn()); 2] = [("spinner", spinner_task), ("clock", clock_task)];
+
+fn spinner_task() {
+    let frames = b"|/-\\";
+    let mut i = 0;
+    loop {
+        put_char(0, WIDTH - 1, frames[i % frames.len()]);
+        i += 1;
+        sleep_ticks(TICKS_PER_SEC / 5);
+    }
+}
+
+fn clock_task() {
+    loop {
+        let secs = ticks() / TICKS_PER_SEC;
+        let mut line = LineBuf::new();
+        let _ = write!(line, "{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60);
+        for (i, &c) in line.as_bytes().iter().enumerate() {
+            put_char(0, WIDTH - 11 + i, c);
+        }
+        sleep_ticks(TICKS_PER_SEC / 2);
+    }
+}
+
+unsafe fn ps(ctx: &mut ShellCtx) {
+    ctx.out("PID  STATE     UPTIME  NAME");
+    for task in (*core::ptr::addr_of!(TASKS)).iter().flatten() {
+        let uptime = ticks() - task.started;
+        let state = match task.state {
+            TaskState::Running => "running",
+            TaskState::Ready => "ready",
+            TaskState::Finished => continue,
+        };
+        let mut line = LineBuf::new();
+        let _ = write!(
+            line,
+            "{:>3}  {:<8} {:>5}.{}s  {}",
+            task.pid,
+            state,
+            uptime / TICKS_PER_SEC,
+            uptime % TICKS_PER_SEC / (TICKS_PER_SEC / 10),
+            task.name
+        );
+        ctx.out(line.as_str());
+    }
+}
+
+// --- Filesystem structures and helpers ---
+
+const MAX_FILES: usize = 16;
+const MAX_DIRS: usize = 8;
+const MAX_NAME: usize = 16;
+const MAX_DIR_STORAGE: usize = 32;
+
+// File contents live in a shared pool of fixed-size blocks. Each file records
+// only its first block; BLOCK_NEXT chains the rest, like a tiny FAT.
+const BLOCK_SIZE: usize = 128;
+const NUM_BLOCKS: usize = 256;
+const MAX_FILE_SIZE: usize = 16 * BLOCK_SIZE;
+const BLOCK_FREE: u16 = u16::MAX;
+const BLOCK_END: u16 = u16::MAX - 1;
+
+static mut BLOCKS: [[u8; BLOCK_SIZE]; NUM_BLOCKS] = [[0; BLOCK_SIZE]; NUM_BLOCKS];
+static mut BLOCK_NEXT: [u16; NUM_BLOCKS] = [BLOCK_FREE; NUM_BLOCKS];
+static mut FREE_BLOCKS: usize = NUM_BLOCKS;
+
+#[derive(Clone, Copy)]
+struct File {
+    name: [u8; MAX_NAME],
+    first_block: u16, // BLOCK_END when the file is empty
+    len: usize,
+    // timer tick of the last change to the contents
+    mtime: u64,
+}
+
+unsafe fn alloc_block() -> Option<u16> {
+    let next = &mut *core::ptr::addr_of_mut!(BLOCK_NEXT);
+    let block = next.iter().position(|&n| n == BLOCK_FREE)?;
+    next[block] = BLOCK_END;
+    FREE_BLOCKS -= 1;
+    Some(block as u16)
+}
+
+// Returns every block of a file to the pool and empties it.
+unsafe fn free_blocks(file: &mut File) {
+    let mut block = file.first_block;
+    while block != BLOCK_END {
+        let next = BLOCK_NEXT[block as usize];
+        BLOCK_NEXT[block as usize] = BLOCK_FREE;
+        FREE_BLOCKS += 1;
+        block = next;
+    }
+    file.first_block = BLOCK_END;
+    file.len = 0;
+}
+
+// Copies a file's contents into `buf` and returns their length.
+unsafe fn file_read(file: &File, buf: &mut [u8]) -> usize {
+    let len = file.len.min(buf.len());
+    let mut block = file.first_block;
+    let mut pos = 0;
+    while pos < len && block != BLOCK_END {
+        let n = (len - pos).min(BLOCK_SIZE);
+        buf[pos..pos + n].copy_from_slice(&BLOCKS[block as usize][..n]);
+        pos += n;
+        block = BLOCK_NEXT[block as usize];
+    }
+    pos
+}
+
+// Replaces a file's contents and updates its mtime. Returns how many bytes
+// were stored, which is less than `data.len()` when the file would exceed
+// MAX_FILE_SIZE or the block pool runs out.
+unsafe fn file_write(file: &mut File, data: &[u8]) -> usize {
+    audit(AuditOp::Write, &file.name, b"");
+    free_blocks(file);
+    file.mtime = ticks();
+    let data = &data[..data.len().min(MAX_FILE_SIZE)];
+    let mut last = BLOCK_END;
+    for chunk in data.chunks(BLOCK_SIZE) {
+        let Some(block) = alloc_block() else { break };
+        BLOCKS[block as usize][..chunk.len()].copy_from_slice(chunk);
+        if last == BLOCK_END {
+            file.first_block = block;
+        } else {
+            BLOCK_NEXT[last as usize] = block;
+        }
+        last = block;
+        file.len += chunk.len();
+    }
+    file.len
+}
+
+#[derive(Clone, Copy)]
+struct Directory {
+    name: [u8; MAX_NAME],
+    files: [Option<File>; MAX_FILES],
+    dirs: [Option<usize>; MAX_DIRS], // indexes into DIR_STORAGE
+    parent: Option<usize>,           // index into DIR_STORAGE
+}
+
+const EMPTY_DIR: Directory = Directory {
+    name: [0; MAX_NAME],
+    files: [None; MAX_FILES],
+    dirs: [None; MAX_DIRS],
+    parent: None,
+};
+
+// Pre-allocate all directories statically
+static mut DIR_STORAGE: [Directory; MAX_DIR_STORAGE] = [EMPTY_DIR; MAX_DIR_STORAGE];
+
+static mut DIR_ALLOC_INDEX: usize = 1; // 0 is root
+
+// Root dir is always at index 0
+static mut CURRENT_DIR_IDX: usize = 0;
+
+// DIR_STORAGE slots given back by rmdir, reused before DIR_ALLOC_INDEX
+// moves on
+static mut FREE_DIRS: [usize; MAX_DIR_STORAGE] = [0; MAX_DIR_STORAGE];
+static mut FREE_DIRS_LEN: usize = 0;
+
+unsafe fn alloc_dir() -> Option<usize> {
+    if FREE_DIRS_LEN > 0 {
+        FREE_DIRS_LEN -= 1;
+        return Some(FREE_DIRS[FREE_DIRS_LEN]);
+    }
+    if DIR_ALLOC_INDEX < TRASH_DIR {
+        let idx = DIR_ALLOC_INDEX;
+        DIR_ALLOC_INDEX += 1;
+        Some(idx)
+    } else {
+        None
+    }
+}
+
+unsafe fn free_dir(idx: usize) {
+    DIR_STORAGE[idx] = EMPTY_DIR;
+    FREE_DIRS[FREE_DIRS_LEN] = idx;
+    FREE_DIRS_LEN += 1;
+}
+
+// Number of directories in use, the root included
+unsafe fn dirs_in_use() -> usize {
+    DIR_ALLOC_INDEX - FREE_DIRS_LEN
+}
+
+unsafe fn dir_in_use(idx: usize) -> bool {
+    let free = &*core::ptr::addr_of!(FREE_DIRS);
+    idx < DIR_ALLOC_INDEX && !free[..FREE_DIRS_LEN].contains(&idx)
+}
+
+// Compares a stored name, padded with NULs, against a name typed on the
+// command line or another stored one. Only a NUL ends a name; spaces are
+// part of it, so a stored "my file" matches neither "my" nor "my file.txt".
+fn name_eq(stored: &[u8], typed: &[u8]) -> bool {
+    stored[..name_len(stored)] == typed[..name_len(typed)]
+}
+
+// A file or directory name that is a command's whole argument, e.g. for
+// `touch my notes.txt`. Double quotes around it are dropped.
+fn name_arg(args: &[u8]) -> &[u8] {
+    let args = args.trim_ascii();
+    match args.strip_prefix(b"\"").and_then(|a| a.strip_suffix(b"\"")) {
+        Some(quoted) => quoted,
+        None => args,
+    }
+}
+
+// Splits a name off the front of arguments that go on after it, e.g. for
+// `write <file> <text>`: the name ends at the first space unless it is in
+// double quotes. Returns the name and the rest.
+fn split_name(args: &[u8]) -> (&[u8], &[u8]) {
+    if let Some(quoted) = args.strip_prefix(b"\"") {
+        if let Some(end) = quoted.iter().position(|&c| c == b'"') {
+            let rest = &quoted[end + 1..];
+            return (&quoted[..end], rest.strip_prefix(b" ").unwrap_or(rest));
+        }
+    }
+    match args.iter().position(|&c| c == b' ') {
+        Some(space) => (&args[..space], &args[space + 1..]),
+        None => (args, &[]),
+    }
+}
+
+// The words of a command that takes several names, e.g. `cp <src> <dst>`.
+// Each is split off with split_name, so a name with spaces is quoted.
+fn split_args(mut args: &[u8]) -> impl Iterator<Item = &[u8]> {
+    core::iter::from_fn(move || {
+        args = args.trim_ascii_start();
+        if args.is_empty() {
+            return None;
+        }
+        let (word, rest) = split_name(args);
+        args = rest;
+        Some(word)
+    })
+}
+
+unsafe fn find_dir(dir: &Directory, name: &[u8]) -> Option<usize> {
+    for d in dir.dirs.iter() {
+        if let Some(idx) = d {
+            let subdir = &DIR_STORAGE[*idx];
+            if name_eq(&subdir.name, name) {
+                return Some(*idx);
+            }
+        }
+    }
+    None
+}
+
+unsafe fn find_file<'a>(dir: &'a Directory, name: &[u8]) -> Option<&'a File> {
+    for f in dir.files.iter() {
+        if let Some(file) = f {
+            if name_eq(&file.name, name) {
+                return Some(file);
+            }
+        }
+    }
+    None
+}
+
+unsafe fn find_file_mut<'a>(dir: &'a mut Directory, name: &[u8]) -> Option<&'a mut File> {
+    for f in dir.files.iter_mut() {
+        if let Some(file) = f {
+            if name_eq(&file.name, name) {
+                return Some(file);
+            }
+        }
+    }
+    None
+}
+
+// Cap on files across all directories, on top of the per-directory MAX_FILES
+const MAX_TOTAL_FILES: usize = 128;
+
+// Number of files in every directory, kept up to date by create_file and
+// remove_file
+static mut TOTAL_FILES: usize = 0;
+
+// Creates an empty file in the first free slot and returns the slot index,
+// or the reason it can't: a name longer than MAX_NAME is refused rather than
+// cut short, since no later command could find the file under the name typed.
+unsafe fn create_file(dir: &mut Directory, name: &[u8]) -> Result<usize, &'static str> {
+    if name.len() > MAX_NAME {
+        return Err("Name too long");
+    }
+    if TOTAL_FILES >= MAX_TOTAL_FILES {
+        return Err("Too many files");
+    }
+    for (i, f) in dir.files.iter_mut().enumerate() {
+        if f.is_none() {
+            let mut new_file = File {
+                name: [0u8; MAX_NAME],
+                first_block: BLOCK_END,
+                len: 0,
+                mtime: ticks(),
+            };
+            new_file.name[..name.len()].copy_from_slice(name);
+            audit(AuditOp::Create, &new_file.name, b"");
+            *f = Some(new_file);
+            TOTAL_FILES += 1;
+            index_add(dir_index(dir), i);
+            return Ok(i);
+        }
+    }
+    Err("Directory full")
+}
+
+unsafe fn remove_file(dir: &mut Directory, slot: usize) {
+    if let Some(mut file) = dir.files[slot].take() {
+        audit(AuditOp::Delete, &file.name, b"");
+        free_blocks(&mut file);
+        TOTAL_FILES -= 1;
+        index_remove(dir_index(dir), slot);
+    }
+}
+
+// Position of a directory within DIR_STORAGE
+unsafe fn dir_index(dir: &Directory) -> usize {
+    (dir as *const Directory).offset_from(core::ptr::addr_of!(DIR_STORAGE) as *const Directory) as usize
+}
+
+// Flat index of every file as (directory, slot), kept up to date by
+// create_file and remove_file so `locate` doesn't have to walk the tree.
+static mut FILE_INDEX: [Option<(usize, usize)>; MAX_TOTAL_FILES] = [None; MAX_TOTAL_FILES];
+
+unsafe fn index_add(dir_idx: usize, slot: usize) {
+    let index = &mut *core::ptr::addr_of_mut!(FILE_INDEX);
+    if let Some(entry) = index.iter_mut().find(|e| e.is_none()) {
+        *entry = Some((dir_idx, slot));
+    }
+}
+
+unsafe fn index_remove(dir_idx: usize, slot: usize) {
+    let index = &mut *core::ptr::addr_of_mut!(FILE_INDEX);
+    if let Some(entry) = index.iter_mut().find(|e| **e == Some((dir_idx, slot))) {
+        *entry = None;
+    }
+}
+
+// Rebuilds the file index from the directories themselves, in case it ever
+// disagrees with them. Returns the number of files indexed.
+unsafe fn rebuild_file_index() -> usize {
+    *core::ptr::addr_of_mut!(FILE_INDEX) = [None; MAX_TOTAL_FILES];
+    let mut count = 0;
+    let storage = &*core::ptr::addr_of!(DIR_STORAGE);
+    // trashed files are deliberately left out
+    for (dir_idx, dir) in storage.iter().enumerate().take(TRASH_DIR) {
+        for (slot, file) in dir.files.iter().enumerate() {
+            if file.is_some() {
+                index_add(dir_idx, slot);
+                count += 1;
+            }
+        }
+    }
+    count
+}
+
+// Returns the slot index of an existing file, or creates an empty one.
+unsafe fn find_or_create_file(dir: &mut Directory, name: &[u8]) -> Result<usize, &'static str> {
+    for (i, f) in dir.files.iter().enumerate() {
+        if let Some(file) = f {
+            if name_eq(&file.name, name) {
+                return Ok(i);
+            }
+        }
+    }
+    create_file(dir, name)
+}
+
+// `rmdir <dir>` removes an empty subdirectory of the current one and gives
+// its DIR_STORAGE slot back for reuse.
+unsafe fn rmdir(ctx: &mut ShellCtx, name: &[u8]) {
+    if name.is_empty() {
+        return ctx.err("Usage: rmdir <dir>");
+    }
+    let dir = &mut DIR_STORAGE[CURRENT_DIR_IDX];
+    let Some(idx) = find_dir(dir, name) else {
+        return ctx.err("No such directory");
+    };
+    let target = &DIR_STORAGE[idx];
+    if target.files.iter().any(Option::is_some) || target.dirs.iter().any(Option::is_some) {
+        return ctx.err("Directory not empty");
+    }
+    if let Some(entry) = dir.dirs.iter_mut().find(|d| **d == Some(idx)) {
+        *entry = None;
+    }
+    // files trashed from it are restored to its parent instead, since the
+    // slot may soon hold a different directory
+    let trash = &mut *core::ptr::addr_of_mut!(TRASH);
+    for origin in trash[..TRASH_LEN].iter_mut() {
+        if origin.1 == idx {
+            origin.1 = CURRENT_DIR_IDX;
+        }
+    }
+    // and so is a file left open in the editor
+    if let Some(session) = (*core::ptr::addr_of_mut!(EDIT)).as_mut().filter(|s| s.dir_idx == idx) {
+        session.dir_idx = CURRENT_DIR_IDX;
+    }
+    free_dir(idx);
+    ctx.out("Directory removed");
+}
+
+// --- Trash ---
+
+// `rm` moves files into the last DIR_STORAGE entry, which is never linked
+// into the tree, so `undo` can put the most recent one back. Only TRASH_SIZE
+// files are kept; beyond that the oldest is deleted for good.
+const TRASH_DIR: usize = MAX_DIR_STORAGE - 1;
+const TRASH_SIZE: usize = 4;
+
+// (slot in TRASH_DIR, directory the file came from), oldest first
+static mut TRASH: [(usize, usize); TRASH_SIZE] = [(0, 0); TRASH_SIZE];
+static mut TRASH_LEN: usize = 0;
+
+unsafe fn trash_file(dir_idx: usize, slot: usize) {
+    if TRASH_LEN == TRASH_SIZE {
+        remove_file(&mut DIR_STORAGE[TRASH_DIR], TRASH[0].0);
+        (*core::ptr::addr_of_mut!(TRASH)).copy_within(1.., 0);
+        TRASH_LEN -= 1;
+    }
+    let trash = &mut DIR_STORAGE[TRASH_DIR];
+    let Some(trash_slot) = trash.files.iter().position(Option::is_none) else { return };
+    let Some(file) = DIR_STORAGE[dir_idx].files[slot].take() else { return };
+    index_remove(dir_idx, slot);
+    audit(AuditOp::Delete, &file.name, b"");
+    DIR_STORAGE[TRASH_DIR].files[trash_slot] = Some(file);
+    TRASH[TRASH_LEN] = (trash_slot, dir_idx);
+    TRASH_LEN += 1;
+}
+
+// After removing more files at once than the trash holds: only the last
+// TRASH_SIZE can still be brought back.
+fn report_trash_overflow(ctx: &mut ShellCtx, removed: usize) {
+    let mut line = LineBuf::new();
+    let _ = write!(line, "Only the last {} of {} can be restored with undo", TRASH_SIZE, removed);
+    ctx.err(line.as_str());
+}
+
+// Moves the most recently trashed file back where it came from.
+unsafe fn undo_remove(ctx: &mut ShellCtx) {
+    if TRASH_LEN == 0 {
+        return ctx.err("Trash is empty");
+    }
+    let (trash_slot, dir_idx) = TRASH[TRASH_LEN - 1];
+    let Some(file) = DIR_STORAGE[TRASH_DIR].files[trash_slot] else { return };
+    let dir = &mut DIR_STORAGE[dir_idx];
+    if find_file(dir, &file.name).is_some() {
+        return ctx.err("A file with that name exists");
+    }
+    let Some(slot) = dir.files.iter().position(Option::is_none) else {
+        return ctx.err("No space for file");
+    };
+    dir.files[slot] = DIR_STORAGE[TRASH_DIR].files[trash_slot].take();
+    index_add(dir_idx, slot);
+    audit(AuditOp::Create, &file.name, b"");
+    TRASH_LEN -= 1;
+    let mut line = LineBuf::new();
+    line.push_bytes(b"Restored ");
+    line.push_bytes(&file.name[..name_len(&file.name)]);
+    ctx.out_bytes(line.as_bytes());
+}
+
+unsafe fn empty_trash() {
+    let trash = *core::ptr::addr_of!(TRASH);
+    for &(trash_slot, _) in &trash[..TRASH_LEN] {
+        remove_file(&mut DIR_STORAGE[TRASH_DIR], trash_slot);
+    }
+    TRASH_LEN = 0;
+}
+
+// Empties the whole filesystem, leaving only the root directory.
+unsafe fn init_filesystem() {
+    // One directory at a time; the whole array is too big for the stack
+    let storage = &mut *core::ptr::addr_of_mut!(DIR_STORAGE);
+    for dir in storage.iter_mut() {
+        *dir = EMPTY_DIR;
+    }
+    DIR_STORAGE[0].name[0] = b'/';
+    DIR_ALLOC_INDEX = 1;
+    FREE_DIRS_LEN = 0;
+    CURRENT_DIR_IDX = 0;
+    TOTAL_FILES = 0;
+    TRASH_LEN = 0;
+    *core::ptr::addr_of_mut!(FILE_INDEX) = [None; MAX_TOTAL_FILES];
+    *core::ptr::addr_of_mut!(BLOCK_NEXT) = [BLOCK_FREE; NUM_BLOCKS];
+    FREE_BLOCKS = NUM_BLOCKS;
+}
+
+// --- Audit log ---
+
+// When enabled with `audit on`, every file create, write, delete and rename
+// is recorded here with its tick. The oldest entries are overwritten.
+const AUDIT_SIZE: usize = 32;
+
+#[derive(Clone, Copy)]
+enum AuditOp {
+    Create,
+    Write,
+    Delete,
+    Rename,
+}
+
+#[derive(Clone, Copy)]
+struct AuditEntry {
+    tick: u64,
+    op: AuditOp,
+    name: [u8; MAX_NAME],
+    new_name: [u8; MAX_NAME], // only for renames
+}
+
+static mut AUDIT_ENABLED: bool = false;
+static mut AUDIT_LOG: [Option<AuditEntry>; AUDIT_SIZE] = [None; AUDIT_SIZE];
+static mut AUDIT_NEXT: usize = 0;
+
+unsafe fn audit(op: AuditOp, name: &[u8], new_name: &[u8]) {
+    if !AUDIT_ENABLED {
+        return;
+    }
+    let mut entry = AuditEntry { tick: ticks(), op, name: [0; MAX_NAME], new_name: [0; MAX_NAME] };
+    let name = &name[..name_len(name)];
+    entry.name[..name.len()].copy_from_slice(name);
+    let new_name = &new_name[..new_name.len().min(MAX_NAME)];
+    entry.new_name[..new_name.len()].copy_from_slice(new_name);
+    AUDIT_LOG[AUDIT_NEXT] = Some(entry);
+    AUDIT_NEXT = (AUDIT_NEXT + 1) % AUDIT_SIZE;
+}
+
+// `audit on|off` toggles recording, `audit -c` empties the log and plain
+// `audit` prints it oldest first.
+unsafe fn audit_command(ctx: &mut ShellCtx, args: &[u8]) {
+    match args {
+        b"on" => {
+            AUDIT_ENABLED = true;
+            ctx.out("Audit log on");
+        }
+        b"off" => {
+            AUDIT_ENABLED = false;
+            ctx.out("Audit log off");
+        }
+        b"-c" => {
+            *core::ptr::addr_of_mut!(AUDIT_LOG) = [None; AUDIT_SIZE];
+            AUDIT_NEXT = 0;
+        }
+        b"" => {
+            let log = &*core::ptr::addr_of!(AUDIT_LOG);
+            for i in 0..AUDIT_SIZE {
+                let Some(entry) = log[(AUDIT_NEXT + i) % AUDIT_SIZE] else { continue };
+                let op = match entry.op {
+                    AuditOp::Create => "create",
+                    AuditOp::Write => "write ",
+                    AuditOp::Delete => "delete",
+                    AuditOp::Rename => "rename",
+                };
+                let mut line = LineBuf::new();
+                let _ = write!(line, "{:>8} {} ", entry.tick, op);
+                line.push_bytes(&entry.name[..name_len(&entry.name)]);
+                if let AuditOp::Rename = entry.op {
+                    line.push_bytes(b" -> ");
+                    line.push_bytes(&entry.new_name[..name_len(&entry.new_name)]);
+                }
+                ctx.out_paged(line.as_bytes());
+            }
+        }
+        _ => ctx.err("Usage: audit [on|off|-c]"),
+    }
+}
+
+// --- Text helpers ---
+
+// Splits text into lines, accepting both "\n" and "\r\n" endings. A final
+// line terminator does not produce an extra empty line. Can be walked from
+// either end.
+fn lines(data: &[u8]) -> impl DoubleEndedIterator<Item = &[u8]> {
+    let end = if data.last() == Some(&b'\n') { data.len() - 1 } else { data.len() };
+    data[..end]
+        .split(|&c| c == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+}
+
+// Number parsing shared by every command. Surrounding spaces are ignored;
+// empty input, any other stray character or overflow gives None.
+
+fn parse_u32(s: &[u8]) -> Option<u32> {
+    let s = s.trim_ascii();
+    if s.is_empty() {
+        return None;
+    }
+    let mut n: u32 = 0;
+    for &c in s {
+        if !c.is_ascii_digit() {
+            return None;
+        }
+        n = n.checked_mul(10)?.checked_add((c - b'0') as u32)?;
+    }
+    Some(n)
+}
+
+// Hexadecimal, with an optional "0x" prefix
+fn parse_hex(s: &[u8]) -> Option<u32> {
+    let s = s.trim_ascii();
+    let digits = s.strip_prefix(b"0x").or_else(|| s.strip_prefix(b"0X")).unwrap_or(s);
+    if digits.is_empty() {
+        return None;
+    }
+    let mut n: u32 = 0;
+    for &c in digits {
+        n = n.checked_mul(16)?.checked_add(hex_digit(c)? as u32)?;
+    }
+    Some(n)
+}
+
+// Parses "start,end" (either side may be left out) into an inclusive range of
+// 1-based line numbers. Line 0 is treated as line 1; an end before the start
+// is an error.
+fn parse_line_range(s: &[u8]) -> Option<(usize, usize)> {
+    let comma = s.iter().position(|&c| c == b',')?;
+    let first = match &s[..comma] {
+        b"" => 1,
+        n => (parse_u32(n)? as usize).max(1),
+    };
+    let last = match &s[comma + 1..] {
+        b"" => usize::MAX,
+        n => parse_u32(n)? as usize,
+    };
+    if last < first {
+        return None;
+    }
+    Some((first, last))
+}
+
+// Matches a name against a pattern in which `*` stands for any run of
+// characters and `?` for any single character.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // position of the last `*` seen, and where in `name` it started matching
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                // let the last `*` swallow one more character
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+fn is_glob(s: &[u8]) -> bool {
+    s.iter().any(|&c| c == b'*' || c == b'?')
+}
+
+fn trim_trailing_slashes(path: &[u8]) -> &[u8] {
+    let mut end = path.len();
+    while end > 1 && path[end - 1] == b'/' {
+        end -= 1;
+    }
+    &path[..end]
+}
+
+// Final segment of a path: "a/b/" -> "b", "/" -> "/"
+fn basename(path: &[u8]) -> &[u8] {
+    let path = trim_trailing_slashes(path);
+    if path == b"/" {
+        return path;
+    }
+    match path.iter().rposition(|&c| c == b'/') {
+        Some(i) => &path[i + 1..],
+        None => path,
+    }
+}
+
+// Everything before the final segment: "a/b" -> "a", "b" -> ".", "/b" -> "/"
+fn dirname(path: &[u8]) -> &[u8] {
+    let path = trim_trailing_slashes(path);
+    match path.iter().rposition(|&c| c == b'/') {
+        Some(0) => b"/",
+        Some(i) => trim_trailing_slashes(&path[..i]),
+        None => b".",
+    }
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+// Parses one line of a hex dump back into bytes, appending them to `out`.
+// Accepted layouts:
+//   xxd:          "00000000: 6865 6c6c 6f0a  hello."
+//   hexdump -C:   "00000000  68 65 6c 6c 6f 0a  |hello.|"
+//   plain:        "68656c6c6f0a"
+// The offset column and the ASCII gutter are ignored. hexdump -C ends with a
+// line holding only the final offset; a bare 8-digit line counts as one once
+// an earlier line had an offset column, which `offsets` tracks across lines.
+// Returns the number of bytes decoded (some may not have fit in `out`).
+fn unhex_line(line: &[u8], out: &mut [u8], out_len: &mut usize, offsets: &mut bool) -> Result<usize, &'static str> {
+    let line = line.trim_ascii_end();
+    // hexdump -C writes * in place of rows repeating the one above
+    if line == b"*" {
+        return Err("Repeated rows (*) not supported; dump with hexdump -Cv");
+    }
+    let first_end = line.iter().position(|&c| c == b' ').unwrap_or(line.len());
+    let is_offset = first_end == 8 && line[..8].iter().all(|&c| hex_digit(c).is_some());
+    let mut hex = line;
+    if first_end > 0 && line[first_end - 1] == b':' {
+        *offsets = true;
+        // xxd separates the ASCII gutter with two spaces
+        hex = &line[first_end..];
+        let mut i = 0;
+        while i < hex.len() && hex[i] == b' ' {
+            i += 1;
+        }
+        let body_start = i;
+        while i + 1 < hex.len() && !(hex[i] == b' ' && hex[i + 1] == b' ') {
+            i += 1;
+        }
+        if i + 1 >= hex.len() {
+            i = hex.len();
+        }
+        hex = &hex[body_start..i];
+    } else if is_offset && (first_end < line.len() || *offsets) {
+        *offsets = true;
+        hex = &line[first_end..];
+        if let Some(bar) = hex.iter().position(|&c| c == b'|') {
+            hex = &hex[..bar];
+        }
+    } else if let Some(bar) = line.iter().position(|&c| c == b'|') {
+        hex = &line[..bar];
+    }
+
+    let invalid = "Invalid hex dump";
+    let mut decoded = 0;
+    for group in hex.split(|&c| c == b' ' || c == b'\t') {
+        if group.len() % 2 != 0 {
+            return Err(invalid);
+        }
+        for pair in group.chunks(2) {
+            let hi = hex_digit(pair[0]).ok_or(invalid)?;
+            let lo = hex_digit(pair[1]).ok_or(invalid)?;
+            if *out_len < out.len() {
+                out[*out_len] = (hi << 4) | lo;
+                *out_len += 1;
+            }
+            decoded += 1;
+        }
+    }
+    Ok(decoded)
+}
+
+// --- Arithmetic ---
+
+// Recursive-descent evaluator for integer expressions: + - * / % with the
+// usual precedence, parentheses and unary minus. Overflow and division by
+// zero make the whole expression invalid.
+struct ExprParser<'a> {
+    s: &'a [u8],
+    pos: usize,
+}
+
+impl ExprParser<'_> {
+    fn peek(&mut self) -> Option<u8> {
+        while self.s.get(self.pos) == Some(&b' ') {
+            self.pos += 1;
+        }
+        self.s.get(self.pos).copied()
+    }
+
+    fn expr(&mut self) -> Option<i64> {
+        let mut value = self.term()?;
+        loop {
+            match self.peek() {
+                Some(b'+') => {
+                    self.pos += 1;
+                    value = value.checked_add(self.term()?)?;
+                }
+                Some(b'-') => {
+                    self.pos += 1;
+                    value = value.checked_sub(self.term()?)?;
+                }
+                _ => return Some(value),
+            }
+        }
+    }
+
+    fn term(&mut self) -> Option<i64> {
+        let mut value = self.factor()?;
+        loop {
+            match self.peek() {
+                Some(b'*') => {
+                    self.pos += 1;
+                    value = value.checked_mul(self.factor()?)?;
+                }
+                Some(b'/') => {
+                    self.pos += 1;
+                    value = value.checked_div(self.factor()?)?;
+                }
+                Some(b'%') => {
+                    self.pos += 1;
+                    value = value.checked_rem(self.factor()?)?;
+                }
+                _ => return Some(value),
+            }
+        }
+    }
+
+    fn factor(&mut self) -> Option<i64> {
+        match self.peek()? {
+            b'-' => {
+                self.pos += 1;
+                self.factor()?.checked_neg()
+            }
+            b'(' => {
+                self.pos += 1;
+                let value = self.expr()?;
+                if self.peek()? != b')' {
+                    return None;
+                }
+                self.pos += 1;
+                Some(value)
+            }
+            _ => {
+                let start = self.pos;
+                while self.s.get(self.pos).is_some_and(u8::is_ascii_digit) {
+                    self.pos += 1;
+                }
+                parse_u32(&self.s[start..self.pos]).map(i64::from)
+            }
+        }
+    }
+}
+
+fn eval_expr(s: &[u8]) -> Option<i64> {
+    let mut parser = ExprParser { s, pos: 0 };
+    let value = parser.expr()?;
+    match parser.peek() {
+        None => Some(value),
+        Some(_) => None,
+    }
+}
+
+// Copies `text` into `out`, replacing every `$((expr))` with its value.
+// Returns false if an expression is unterminated or invalid.
+fn expand_arith(text: &[u8], out: &mut LineBuf) -> bool {
+    let mut i = 0;
+    while i < text.len() {
+        if !text[i..].starts_with(b"$((") {
+            out.push_bytes(&text[i..i + 1]);
+            i += 1;
+            continue;
+        }
+        // find the "))" that closes this expansion, skipping nested parens
+        let start = i + 3;
+        let mut depth = 0;
+        let mut end = None;
+        for (j, &c) in text.iter().enumerate().skip(start) {
+            match c {
+                b'(' => depth += 1,
+                b')' if depth > 0 => depth -= 1,
+                b')' if text.get(j + 1) == Some(&b')') => {
+                    end = Some(j);
+                    break;
+                }
+                b')' => return false,
+                _ => {}
+            }
+        }
+        let Some(end) = end else { return false };
+        let Some(value) = eval_expr(&text[start..end]) else { return false };
+        let _ = write!(out, "{}", value);
+        i = end + 2;
+    }
+    true
+}
+
+// --- Command history ---
+
+const HISTORY_SIZE: usize = 8;
+const HISTORY_WIDTH: usize = CMD_MAX;
+
+static mut HISTORY: [[u8; HISTORY_WIDTH]; HISTORY_SIZE] = [[0; HISTORY_WIDTH]; HISTORY_SIZE];
+static mut HISTORY_LENS: [usize; HISTORY_SIZE] = [0; HISTORY_SIZE];
+static mut HISTORY_NEXT: usize = 0; // slot the next command is written to
+static mut HISTORY_COUNT: usize = 0; // number of valid entries
+
+unsafe fn history_push(cmd: &[u8]) {
+    let len = cmd.len().min(HISTORY_WIDTH);
+    HISTORY[HISTORY_NEXT][..len].copy_from_slice(&cmd[..len]);
+    HISTORY_LENS[HISTORY_NEXT] = len;
+    HISTORY_NEXT = (HISTORY_NEXT + 1) % HISTORY_SIZE;
+    if HISTORY_COUNT < HISTORY_SIZE {
+        HISTORY_COUNT += 1;
+    }
+}
+
+// Entry `n` counting from the oldest retained command (0-based).
+unsafe fn history_get(n: usize) -> Option<&'static [u8]> {
+    if n >= HISTORY_COUNT {
+        return None;
+    }
+    let slot = (HISTORY_NEXT + HISTORY_SIZE - HISTORY_COUNT + n) % HISTORY_SIZE;
+    Some(&HISTORY[slot][..HISTORY_LENS[slot]])
+}
+
+// Resolves the part after `!`: `!` for the last command, a number as listed
+// by `history`, or otherwise the most recent command starting with it.
+unsafe fn history_expand(event: &[u8]) -> Option<&'static [u8]> {
+    if event == b"!" {
+        return history_get(HISTORY_COUNT.checked_sub(1)?);
+    }
+    if let Some(n) = parse_u32(event) {
+        return history_get((n as usize).checked_sub(1)?);
+    }
+    (0..HISTORY_COUNT).rev().filter_map(|n| history_get(n)).find(|entry| entry.starts_with(event))
+}
+
+unsafe fn history_clear() {
+    HISTORY = [[0; HISTORY_WIDTH]; HISTORY_SIZE];
+    HISTORY_LENS = [0; HISTORY_SIZE];
+    HISTORY_NEXT = 0;
+    HISTORY_COUNT = 0;
+}
+
+// What to do when a copy or move would replace an existing file
+#[derive(Clone, Copy, PartialEq)]
+enum Overwrite {
+    Ask,
+    Force,
+    Skip,
+}
+
+// Shared by `cp` and `mv`: `cp [-f|-n] [-p] <src> <dst>` / `mv [-f|-n] <src> <dst>`.
+// Without a flag the user is asked before an existing destination is replaced.
+// A copy gets the current time as its mtime unless -p preserves the source's.
+// <dst> may be a subdirectory (or ..), which keeps the file's name; a <src>
+// with `*` or `?` copies or moves every matching file into such a directory.
+unsafe fn copy_or_move(ctx: &mut ShellCtx, args: &[u8], is_move: bool) {
+    let usage = if is_move { "Usage: mv [-f|-n] <src> <dst>" } else { "Usage: cp [-f|-n] [-p] <src> <dst>" };
+    let mut mode = Overwrite::Ask;
+    let mut preserve = false;
+    let mut src: Option<&[u8]> = None;
+    let mut dst: Option<&[u8]> = None;
+    for arg in split_args(args) {
+        match arg {
+            b"-f" => mode = Overwrite::Force,
+            b"-n" => mode = Overwrite::Skip,
+            b"-p" if !is_move => preserve = true,
+            _ if src.is_none() => src = Some(arg),
+            _ if dst.is_none() => dst = Some(arg),
+            _ => {
+                ctx.err(usage);
+                return;
+            }
+        }
+    }
+    let (src, dst) = match (src, dst) {
+        (Some(src), Some(dst)) => (src, dst),
+        _ => {
+            ctx.err(usage);
+            return;
+        }
+    };
+
+    let dst_dir = match trim_trailing_slashes(dst) {
+        b".." => DIR_STORAGE[CURRENT_DIR_IDX].parent,
+        name => find_dir(&DIR_STORAGE[CURRENT_DIR_IDX], name),
+    };
+    if dst_dir.is_none() && dst.ends_with(b"/") {
+        return ctx.err("No such directory");
+    }
+
+    if is_glob(src) {
+        let Some(dst_dir) = dst_dir else {
+            return ctx.err("Target of a wildcard must be a directory");
+        };
+        let mut count = 0;
+        for slot in 0..MAX_FILES {
+            let Some(file) = DIR_STORAGE[CURRENT_DIR_IDX].files[slot] else { continue };
+            let name = &file.name[..name_len(&file.name)];
+            if glob_match(src, name) && copy_one(ctx, slot, dst_dir, name, mode, preserve, is_move) {
+                count += 1;
+            }
+        }
+        let mut line = LineBuf::new();
+        let _ = write!(line, "{} {} file(s)", if is_move { "Moved" } else { "Copied" }, count);
+        return ctx.out(line.as_str());
+    }
+
+    let dir = &DIR_STORAGE[CURRENT_DIR_IDX];
+    let src_slot = dir.files.iter().position(|f| matches!(f, Some(file) if name_eq(&file.name, src)));
+    let src_slot = match src_slot {
+        Some(slot) => slot,
+        None => {
+            ctx.err("No such file");
+            return;
+        }
+    };
+    let (dst_dir, dst_name) = match dst_dir {
+        Some(dir_idx) => (dir_idx, &src[..name_len(src)]),
+        None => (CURRENT_DIR_IDX, dst),
+    };
+    if copy_one(ctx, src_slot, dst_dir, dst_name, mode, preserve, is_move) {
+        ctx.out(if is_move { "Moved file" } else { "Copied file" });
+    }
+}
+
+// Copies or moves one file of the current directory to `dst_name` in
+// directory `dst_dir`. Reports problems itself; returns whether it happened.
+unsafe fn copy_one(
+    ctx: &mut ShellCtx,
+    src_slot: usize,
+    dst_dir: usize,
+    dst_name: &[u8],
+    mode: Overwrite,
+    preserve: bool,
+    is_move: bool,
+) -> bool {
+    let src_dir = CURRENT_DIR_IDX;
+    let dst_slot = DIR_STORAGE[dst_dir]
+        .files
+        .iter()
+        .position(|f| matches!(f, Some(file) if name_eq(&file.name, dst_name)));
+    if dst_dir == src_dir && dst_slot == Some(src_slot) {
+        ctx.err("Source and destination are the same file");
+        return false;
+    }
+
+    if dst_slot.is_some() {
+        let overwrite = match mode {
+            Overwrite::Force => true,
+            Overwrite::Skip => false,
+            Overwrite::Ask => {
+                let mut question = [0u8; WIDTH];
+                let name_len = dst_name.len().min(MAX_NAME);
+                question[..10].copy_from_slice(b"overwrite ");
+                question[10..10 + name_len].copy_from_slice(&dst_name[..name_len]);
+                question[10 + name_len] = b'?';
+                confirm(ctx, &question[..11 + name_len])
+            }
+        };
+        if !overwrite {
+            ctx.out("Not overwritten");
+            return false;
+        }
+    }
+
+    let name_len = dst_name.len().min(MAX_NAME);
+    if is_move {
+        // a cut-short name would be a file no later command finds
+        if dst_name.len() > MAX_NAME {
+            ctx.err("Name too long");
+            return false;
+        }
+        if let Some(slot) = dst_slot {
+            remove_file(&mut DIR_STORAGE[dst_dir], slot);
+        }
+        let slot = if dst_dir == src_dir {
+            src_slot
+        } else {
+            let Some(slot) = DIR_STORAGE[dst_dir].files.iter().position(Option::is_none) else {
+                ctx.err("No space for file");
+                return false;
+            };
+            DIR_STORAGE[dst_dir].files[slot] = DIR_STORAGE[src_dir].files[src_slot].take();
+            index_remove(src_dir, src_slot);
+            index_add(dst_dir, slot);
+            slot
+        };
+        if let Some(file) = DIR_STORAGE[dst_dir].files[slot].as_mut() {
+            audit(AuditOp::Rename, &file.name, dst_name);
+            file.name = [0; MAX_NAME];
+            file.name[..name_len].copy_from_slice(&dst_name[..name_len]);
+        }
+        true
+    } else {
+        let slot = match dst_slot {
+            Some(slot) => slot,
+            None => match create_file(&mut DIR_STORAGE[dst_dir], dst_name) {
+                Ok(slot) => slot,
+                Err(msg) => {
+                    ctx.err(msg);
+                    return false;
+                }
+            },
+        };
+        let mut data = [0u8; MAX_FILE_SIZE];
+        let (len, src_mtime) = match &DIR_STORAGE[src_dir].files[src_slot] {
+            Some(file) => (file_read(file, &mut data), file.mtime),
+            None => (0, 0),
+        };
+        let Some(copy) = DIR_STORAGE[dst_dir].files[slot].as_mut() else { return false };
+        let written = file_write(copy, &data[..len]);
+        if preserve {
+            copy.mtime = src_mtime;
+        }
+        if written < len {
+            ctx.err("File truncated");
+        }
+        true
+    }
+}
+
+// Saves the text of the screen rows above the command line into a file. The
+// screen can hold more than MAX_FILE_SIZE bytes, so trailing blanks are trimmed
+// and only the most recent rows that fit are kept.
+unsafe fn screenshot(ctx: &mut ShellCtx, name: &[u8]) {
+    if name.is_empty() {
+        ctx.err("Usage: screenshot <file>");
+        return;
+    }
+    // ctx.row is the output row; the command itself is on the row above
+    let end = ctx.row.saturating_sub(1);
+    let mut first = end;
+    let mut size = 0;
+    while first > 0 {
+        let len = vga_row_text_len(first - 1);
+        if size + len + 1 > MAX_FILE_SIZE {
+            break;
+        }
+        size += len + 1;
+        first -= 1;
+    }
+
+    let dir = &mut DIR_STORAGE[CURRENT_DIR_IDX];
+    let file = match find_or_create_file(dir, name) {
+        Ok(i) => dir.files[i].as_mut(),
+        Err(msg) => return ctx.err(msg),
+    };
+    if let Some(file) = file {
+        let mut text = [0u8; MAX_FILE_SIZE];
+        let mut len = 0;
+        for r in first..end {
+            for c in 0..vga_row_text_len(r) {
+                text[len] = *VGA_BUFFER.add((r * WIDTH + c) * 2);
+                len += 1;
+            }
+            text[len] = b'\n';
+            len += 1;
+        }
+        if file_write(file, &text[..len]) < len {
+            ctx.err("File truncated");
+        } else {
+            ctx.out("Saved screen to file");
+        }
+    }
+}
+
+// Length of the text on a screen row, ignoring trailing blanks.
+fn vga_row_text_len(row: usize) -> usize {
+    let mut len = WIDTH;
+    while len > 0 {
+        let c = unsafe { *VGA_BUFFER.add((row * WIDTH + len - 1) * 2) };
+        if c != b' ' && c != 0 {
+            break;
+        }
+        len -= 1;
+    }
+    len
+}
+
+// Code page 437 box-drawing characters, written to VGA memory as-is
+const BOX_VERTICAL: u8 = 0xB3; // │
+const BOX_BRANCH: u8 = 0xC3; // ├
+const BOX_CORNER: u8 = 0xC0; // └
+const BOX_HORIZONTAL: u8 = 0xC4; // ─
+
+#[derive(Clone, Copy)]
+enum Entry {
+    Dir(usize),  // index into DIR_STORAGE
+    File(usize), // slot in the directory's files
+}
+
+// The n-th entry of a directory in listing order: subdirectories first, then
+// files, skipping empty slots.
+fn nth_entry(dir: &Directory, n: usize) -> Option<Entry> {
+    let dirs = dir.dirs.iter().filter_map(|d| d.map(Entry::Dir));
+    let files = dir.files.iter().enumerate().filter(|(_, f)| f.is_some()).map(|(i, _)| Entry::File(i));
+    dirs.chain(files).nth(n)
+}
+
+// Length of a stored name: it is padded with NULs and may contain spaces
+fn name_len(name: &[u8]) -> usize {
+    name.iter().position(|&c| c == 0).unwrap_or(name.len())
+}
+
+// Visits every entry below directory `start` in listing order, depth first.
+// `visit` gets the depth (0 for direct children), the directory holding the
+// entry, the entry, and whether it is the last entry of that directory. Walks
+// with an explicit stack instead of recursion.
+unsafe fn walk_tree(start: usize, mut visit: impl FnMut(usize, usize, Entry, bool)) {
+    // (directory, index of its next entry to visit)
+    let mut stack = [(0usize, 0usize); MAX_DIR_STORAGE];
+    stack[0] = (start, 0);
+    let mut depth = 1;
+
+    while depth > 0 {
+        let (dir_idx, n) = stack[depth - 1];
+        let dir = &DIR_STORAGE[dir_idx];
+        let entry = match nth_entry(dir, n) {
+            Some(entry) => entry,
+            None => {
+                depth -= 1;
+                continue;
+            }
+        };
+        stack[depth - 1].1 += 1;
+        let is_last = nth_entry(dir, n + 1).is_none();
+        visit(depth - 1, dir_idx, entry, is_last);
+
+        if let Entry::Dir(idx) = entry {
+            if depth < MAX_DIR_STORAGE {
+                stack[depth] = (idx, 0);
+                depth += 1;
+            }
+        }
+    }
+}
+
+unsafe fn entry_name(dir_idx: usize, entry: Entry) -> &'static [u8] {
+    let name: &'static [u8] = match entry {
+        Entry::Dir(idx) => &DIR_STORAGE[idx].name,
+        Entry::File(slot) => match &DIR_STORAGE[dir_idx].files[slot] {
+            Some(file) => &file.name,
+            None => &[],
+        },
+    };
+    &name[..name_len(name)]
+}
+
+// Absolute path of an entry, e.g. "/docs/a.txt". Returns its length.
+unsafe fn entry_path(dir_idx: usize, entry: Entry, buf: &mut [u8]) -> usize {
+    match entry {
+        Entry::Dir(idx) => dir_path(idx, buf),
+        Entry::File(_) => {
+            let mut len = dir_path(dir_idx, buf);
+            if len > 1 && len < buf.len() {
+                buf[len] = b'/';
+                len += 1;
+            }
+            let name = entry_name(dir_idx, entry);
+            let n = name.len().min(buf.len() - len);
+            buf[len..len + n].copy_from_slice(&name[..n]);
+            len + n
+        }
+    }
+}
+
+// Draws the directory hierarchy below the current directory with box-drawing
+// connectors.
+unsafe fn tree(ctx: &mut ShellCtx) {
+    // whether the directory at each depth was the last entry of its parent
+    let mut last = [false; MAX_DIR_STORAGE];
+    ctx.out(".");
+    walk_tree(CURRENT_DIR_IDX, |depth, dir_idx, entry, is_last| {
+        let mut line = LineBuf::new();
+        for &ancestor_last in &last[..depth] {
+            line.push_bytes(if ancestor_last { b"    " } else { &[BOX_VERTICAL, b' ', b' ', b' '] });
+        }
+        line.push_bytes(&[if is_last { BOX_CORNER } else { BOX_BRANCH }, BOX_HORIZONTAL, BOX_HORIZONTAL, b' ']);
+        let name = entry_name(dir_idx, entry);
+        if let Entry::Dir(_) = entry {
+            line.push_bytes(b"[");
+            line.push_bytes(name);
+            line.push_bytes(b"]");
+            last[depth] = is_last;
+        } else {
+            line.push_bytes(name);
+        }
+        // a deep tree can be taller than the screen
+        ctx.out_paged(line.as_bytes());
+    });
+}
+
+// --- find ---
+
+// Upper bound on matches a single find can act on
+const MAX_FIND: usize = MAX_TOTAL_FILES + MAX_DIR_STORAGE;
+
+#[derive(Clone, Copy, PartialEq)]
+enum FindAction {
+    Print,
+    Cat,
+    Rm,
+    Stat,
+}
+
+// `find [name] [-type f|d] [-exec rm|cat|stat] [-f]`: searches below the
+// current directory and prints or acts on every match. `-exec rm` asks first
+// unless `-f` is given.
+unsafe fn find(ctx: &mut ShellCtx, args: &[u8]) {
+    let usage = "Usage: find [name] [-type f|d] [-exec rm|cat|stat] [-f]";
+    let mut name: Option<&[u8]> = None;
+    let mut want_files = true;
+    let mut want_dirs = true;
+    let mut action = FindAction::Print;
+    let mut force = false;
+    let mut args = split_args(args);
+    while let Some(arg) = args.next() {
+        match arg {
+            b"-type" => match args.next() {
+                Some(b"f") => want_dirs = false,
+                Some(b"d") => want_files = false,
+                _ => return ctx.err(usage),
+            },
+            b"-exec" => {
+                action = match args.next() {
+                    Some(b"rm") => FindAction::Rm,
+                    Some(b"cat") => FindAction::Cat,
+                    Some(b"stat") => FindAction::Stat,
+                    _ => return ctx.err(usage),
+                }
+            }
+            b"-f" => force = true,
+            _ if name.is_none() && !arg.starts_with(b"-") => name = Some(arg),
+            _ => return ctx.err(usage),
+        }
+    }
+
+    let mut matches = [(0usize, Entry::Dir(0)); MAX_FIND];
+    let mut count = 0;
+    walk_tree(CURRENT_DIR_IDX, |_, dir_idx, entry, _| {
+        let wanted = match entry {
+            Entry::Dir(_) => want_dirs,
+            Entry::File(_) => want_files,
+        };
+        let name_matches = match name {
+            Some(name) => name_eq(entry_name(dir_idx, entry), name),
+            None => true,
+        };
+        if wanted && name_matches && count < MAX_FIND {
+            matches[count] = (dir_idx, entry);
+            count += 1;
+        }
+    });
+
+    if count == 0 {
+        return ctx.err("Not found");
+    }
+    if action == FindAction::Rm {
+        let files = matches[..count].iter().filter(|(_, e)| matches!(e, Entry::File(_))).count();
+        // only directories matched; rm leaves those alone
+        if files == 0 {
+            return ctx.out("No files to remove");
+        }
+        let mut question = LineBuf::new();
+        let _ = write!(question, "remove {} file(s)?", files);
+        if !force && !confirm(ctx, question.as_bytes()) {
+            return ctx.out("Nothing removed");
+        }
+    }
+
+    let mut removed = 0;
+    for &(dir_idx, entry) in &matches[..count] {
+        let mut path = [0u8; WIDTH];
+        let path_len = entry_path(dir_idx, entry, &mut path);
+        let path = &path[..path_len];
+        match (action, entry) {
+            // a search from / can match more entries than fit on the screen
+            (FindAction::Print, _) => ctx.out_paged(path),
+            (FindAction::Cat, Entry::File(slot)) => {
+                if let Some(file) = &DIR_STORAGE[dir_idx].files[slot] {
+                    let mut data = [0u8; MAX_FILE_SIZE];
+                    let len = file_read(file, &mut data);
+                    for line in lines(&data[..len]) {
+                        ctx.out_bytes(line);
+                    }
+                }
+            }
+            (FindAction::Stat, _) => stat_entry(ctx, dir_idx, entry, path),
+            (FindAction::Rm, Entry::File(slot)) => {
+                // to the trash, like plain rm, so `undo` works the same
+                trash_file(dir_idx, slot);
+                removed += 1;
+                let mut line = LineBuf::new();
+                line.push_bytes(b"removed ");
+                line.push_bytes(path);
+                ctx.out_bytes(line.as_bytes());
+            }
+            // cat and rm only apply to files
+            (_, Entry::Dir(_)) => {}
+        }
+    }
+    if removed > TRASH_SIZE {
+        report_trash_overflow(ctx, removed);
+    }
+}
+
+// `locate <text>` prints every file whose name contains <text>, using the
+// file index; `locate -u` rebuilds the index.
+unsafe fn locate(ctx: &mut ShellCtx, pattern: &[u8]) {
+    if pattern.is_empty() {
+        return ctx.err("Usage: locate <text> | locate -u");
+    }
+    if pattern == b"-u" {
+        let mut line = LineBuf::new();
+        let _ = write!(line, "Indexed {} files", rebuild_file_index());
+        return ctx.out(line.as_str());
+    }
+    let index = &*core::ptr::addr_of!(FILE_INDEX);
+    for &(dir_idx, slot) in index.iter().flatten() {
+        let entry = Entry::File(slot);
+        if entry_name(dir_idx, entry).windows(pattern.len()).any(|w| w == pattern) {
+            let mut path = [0u8; WIDTH];
+            let len = entry_path(dir_idx, entry, &mut path);
+            ctx.out_bytes(&path[..len]);
+        }
+    }
+}
+
+// Matching lines `grep` prints; further ones are only counted
+const GREP_MAX_LINES: usize = 64;
+
+// `grep <text> <file>` prints the lines of a file containing <text>.
+// `grep -r <text> [dir]` searches every file below a directory (the current
+// one by default), prefixing each hit with the file's path, and ends with the
+// number of matching lines.
+unsafe fn grep(ctx: &mut ShellCtx, args: &[u8]) {
+    let usage = "Usage: grep <text> <file> | grep -r <text> [dir]";
+    let mut args = split_args(args);
+    let (recursive, pattern, target) = match (args.next(), args.next(), args.next(), args.next()) {
+        (Some(b"-r"), Some(pattern), target, None) => (true, pattern, target),
+        (Some(pattern), Some(file), None, None) if pattern != b"-r" => (false, pattern, Some(file)),
+        _ => return ctx.err(usage),
+    };
+
+    let mut hits = 0;
+    let mut files = 0;
+    let mut search = |ctx: &mut ShellCtx, dir_idx: usize, slot: usize| {
+        let Some(file) = &DIR_STORAGE[dir_idx].files[slot] else { return };
+        let mut data = [0u8; MAX_FILE_SIZE];
+        let len = file_read(file, &mut data);
+        let mut path = [0u8; WIDTH];
+        let path_len = entry_path(dir_idx, Entry::File(slot), &mut path);
+        let before = hits;
+        for line in lines(&data[..len]).filter(|line| line.windows(pattern.len()).any(|w| w == pattern)) {
+            if hits < GREP_MAX_LINES {
+                let mut out = [0u8; WIDTH * 2];
+                let mut n = 0;
+                if recursive {
+                    for part in [&path[..path_len], b": "] {
+                        out[n..n + part.len()].copy_from_slice(part);
+                        n += part.len();
+                    }
+                }
+                let m = line.len().min(out.len() - n);
+                out[n..n + m].copy_from_slice(&line[..m]);
+                ctx.out_paged(&out[..n + m]);
+            }
+            hits += 1;
+        }
+        if hits > before {
+            files += 1;
+        }
+    };
+
+    if !recursive {
+        let dir = &DIR_STORAGE[CURRENT_DIR_IDX];
+        let name = target.unwrap_or_default();
+        match dir.files.iter().position(|f| f.as_ref().is_some_and(|f| name_eq(&f.name, name))) {
+            Some(slot) => search(ctx, CURRENT_DIR_IDX, slot),
+            None => return ctx.err("No such file"),
+        }
+        if hits > GREP_MAX_LINES {
+            let mut line = LineBuf::new();
+            let _ = write!(line, "... {} more matching lines", hits - GREP_MAX_LINES);
+            ctx.out(line.as_str());
+        }
+        return;
+    }
+
+    let start = match target {
+        None | Some(b".") => CURRENT_DIR_IDX,
+        Some(name) => match find_dir(&DIR_STORAGE[CURRENT_DIR_IDX], name) {
+            Some(idx) => idx,
+            None => return ctx.err("No such directory"),
+        },
+    };
+    walk_tree(start, |_, dir_idx, entry, _| {
+        if let Entry::File(slot) = entry {
+            search(ctx, dir_idx, slot);
+        }
+    });
+
+    let mut line = LineBuf::new();
+    let _ = write!(line, "{} matching lines in {} files", hits, files);
+    if hits > GREP_MAX_LINES {
+        let _ = write!(line, " ({} shown)", GREP_MAX_LINES);
+    }
+    ctx.out(line.as_str());
+}
+
+// `wc [-lwc] <file>` counts lines, words and bytes. Flags pick which counts
+// are printed, in that fixed order; with none, all three are.
+unsafe fn wc(ctx: &mut ShellCtx, args: &[u8]) {
+    let usage = "Usage: wc [-l] [-w] [-c] <file>";
+    let (mut show_lines, mut show_words, mut show_bytes) = (false, false, false);
+    let mut name: Option<&[u8]> = None;
+    for arg in split_args(args) {
+        match arg.strip_prefix(b"-") {
+            // flags may be combined, as in -lw
+            Some(flags) if !flags.is_empty() => {
+                for &flag in flags {
+                    match flag {
+                        b'l' => show_lines = true,
+                        b'w' => show_words = true,
+                        b'c' => show_bytes = true,
+                        _ => return ctx.err(usage),
+                    }
+                }
+            }
+            _ if name.is_none() => name = Some(arg),
+            _ => return ctx.err(usage),
+        }
+    }
+    let Some(name) = name else { return ctx.err(usage) };
+    let Some(file) = find_file(&DIR_STORAGE[CURRENT_DIR_IDX], name) else {
+        return ctx.err("No such file");
+    };
+    if !(show_lines || show_words || show_bytes) {
+        (show_lines, show_words, show_bytes) = (true, true, true);
+    }
+
+    let mut data = [0u8; MAX_FILE_SIZE];
+    let len = file_read(file, &mut data);
+    let data = &data[..len];
+    let line_count = data.iter().filter(|&&c| c == b'\n').count();
+    let word_count = data.split(|c| c.is_ascii_whitespace()).filter(|w| !w.is_empty()).count();
+
+    // a lone count is printed bare so scripts can use it directly
+    let single = [show_lines, show_words, show_bytes].iter().filter(|&&s| s).count() == 1;
+    let mut line = LineBuf::new();
+    for (show, count) in [(show_lines, line_count), (show_words, word_count), (show_bytes, len)] {
+        if show && single {
+            let _ = write!(line, "{} ", count);
+        } else if show {
+            let _ = write!(line, "{:>7} ", count);
+        }
+    }
+    line.push_bytes(&name[..name_len(name)]);
+    ctx.out(line.as_str());
+}
+
+// `upper [-w] <file>` and `lower [-w] <file>` print a file with its ASCII
+// letters changed to upper or lower case; with -w the file is rewritten
+// instead.
+unsafe fn change_case(ctx: &mut ShellCtx, args: &[u8], upper: bool) {
+    let (write_back, name) = match args.strip_prefix(b"-w ") {
+        Some(name) => (true, name_arg(name)),
+        None => (false, name_arg(args)),
+    };
+    if name.is_empty() {
+        return ctx.err(if upper { "Usage: upper [-w] <file>" } else { "Usage: lower [-w] <file>" });
+    }
+    let Some(file) = find_file_mut(&mut DIR_STORAGE[CURRENT_DIR_IDX], name) else {
+        return ctx.err("No such file");
+    };
+    let mut data = [0u8; MAX_FILE_SIZE];
+    let len = file_read(file, &mut data);
+    if upper {
+        data[..len].make_ascii_uppercase();
+    } else {
+        data[..len].make_ascii_lowercase();
+    }
+    if write_back {
+        file_write(file, &data[..len]);
+        ctx.out("File updated");
+    } else {
+        for line in lines(&data[..len]) {
+            ctx.out_bytes(line);
+        }
+    }
+}
+
+const SYSLOG: &[u8] = b"syslog.txt";
+
+// `log <message>` appends "YYYY-MM-DD HH:MM:SS <message>" to /syslog.txt,
+// creating it if needed. Once the file is full the oldest lines are dropped
+// to make room.
+unsafe fn log_message(ctx: &mut ShellCtx, message: &[u8]) {
+    if message.is_empty() {
+        return ctx.err("Usage: log <message>");
+    }
+    let (year, month, day) = rtc_date();
+    let (h, m, s) = rtc_time();
+    let mut stamp = LineBuf::new();
+    let _ = write!(stamp, "{}-{:02}-{:02} {:02}:{:02}:{:02} ", year, month, day, h, m, s);
+    // room for the timestamp and the longest alias-expanded command line
+    let mut entry = [0u8; WIDTH + CMD_MAX + MAX_VAR_VALUE];
+    let mut entry_len = 0;
+    for part in [stamp.as_bytes(), message, b"\n"] {
+        entry[entry_len..entry_len + part.len()].copy_from_slice(part);
+        entry_len += part.len();
+    }
+
+    let dir = &mut DIR_STORAGE[0];
+    let slot = match find_or_create_file(dir, SYSLOG) {
+        Ok(slot) => slot,
+        Err(msg) => return ctx.err(msg),
+    };
+    let Some(file) = dir.files[slot].as_mut() else { return };
+    let mut data = [0u8; MAX_FILE_SIZE];
+    let mut len = file_read(file, &mut data);
+    let mut start = 0;
+    while len - start + entry_len > MAX_FILE_SIZE {
+        start = match data[start..len].iter().position(|&c| c == b'\n') {
+            Some(i) => start + i + 1,
+            None => len,
+        };
+    }
+    data.copy_within(start..len, 0);
+    len -= start;
+    data[len..len + entry_len].copy_from_slice(&entry[..entry_len]);
+    len += entry_len;
+    if file_write(file, &data[..len]) < len {
+        ctx.err("Log truncated: out of space");
+    } else {
+        ctx.out("Logged");
+    }
+}
+
+// `split <file> <n>` copies each n-byte chunk of a file into <file>.0,
+// <file>.1 and so on; the last piece holds what is left over.
+unsafe fn split(ctx: &mut ShellCtx, args: &[u8]) {
+    let usage = "Usage: split <file> <bytes>";
+    let mut args = split_args(args);
+    let (name, size) = match (args.next(), args.next().and_then(parse_u32), args.next()) {
+        (Some(name), Some(size), None) if size > 0 => (name, size as usize),
+        _ => return ctx.err(usage),
+    };
+    let dir = &mut DIR_STORAGE[CURRENT_DIR_IDX];
+    let Some(file) = find_file(dir, name) else { return ctx.err("No such file") };
+    let mut data = [0u8; MAX_FILE_SIZE];
+    let len = file_read(file, &mut data);
+    let pieces = len.div_ceil(size);
+    // the longest suffix must still fit in a file name
+    let mut suffix = LineBuf::new();
+    let _ = write!(suffix, ".{}", pieces.saturating_sub(1));
+    if name.len() + suffix.len > MAX_NAME {
+        return ctx.err("Name too long for the pieces");
+    }
+
+    let mut written = 0;
+    for (i, chunk) in data[..len].chunks(size).enumerate() {
+        let mut piece = LineBuf::new();
+        piece.push_bytes(name);
+        let _ = write!(piece, ".{}", i);
+        let file = match find_or_create_file(dir, piece.as_bytes()) {
+            Ok(slot) => dir.files[slot].as_mut(),
+            Err(msg) => {
+                ctx.err(msg);
+                break;
+            }
+        };
+        let Some(file) = file else { break };
+        if file_write(file, chunk) < chunk.len() {
+            ctx.err("File truncated");
+            break;
+        }
+        written += 1;
+    }
+    let mut line = LineBuf::new();
+    let _ = write!(line, "Wrote {} of {} pieces", written, pieces);
+    ctx.out(line.as_str());
+}
+
+// `concat <out> <file>...` writes the named files one after another into
+// <out>. Missing files are reported and skipped.
+unsafe fn concat(ctx: &mut ShellCtx, args: &[u8]) {
+    let mut args = split_args(args);
+    let Some(out) = args.next() else { return ctx.err("Usage: concat <out> <file>...") };
+    let dir = &mut DIR_STORAGE[CURRENT_DIR_IDX];
+    let mut data = [0u8; MAX_FILE_SIZE];
+    let mut len = 0;
+    let mut truncated = false;
+    for name in args {
+        let Some(file) = find_file(dir, name) else {
+            let mut line = LineBuf::new();
+            line.push_bytes(name);
+            line.push_bytes(b": no such file, skipped");
+            ctx.err(line.as_str());
+            continue;
+        };
+        truncated |= file.len > MAX_FILE_SIZE - len;
+        len += file_read(file, &mut data[len..]);
+    }
+
+    let slot = match find_or_create_file(dir, out) {
+        Ok(slot) => slot,
+        Err(msg) => return ctx.err(msg),
+    };
+    let Some(file) = dir.files[slot].as_mut() else { return };
+    let written = file_write(file, &data[..len]);
+    if truncated || written < len {
+        ctx.err("File truncated");
+    }
+    let mut line = LineBuf::new();
+    let _ = write!(line, "Wrote {} bytes", written);
+    ctx.out(line.as_str());
+}
+
+// `rev [-w] <file>` prints each line of a file with its characters in
+// reverse order; with -w the file is rewritten instead.
+unsafe fn rev(ctx: &mut ShellCtx, args: &[u8]) {
+    let (write_back, name) = match args.strip_prefix(b"-w ") {
+        Some(name) => (true, name_arg(name)),
+        None => (false, name_arg(args)),
+    };
+    if name.is_empty() {
+        return ctx.err("Usage: rev [-w] <file>");
+    }
+    let Some(file) = find_file_mut(&mut DIR_STORAGE[CURRENT_DIR_IDX], name) else {
+        return ctx.err("No such file");
+    };
+    let mut data = [0u8; MAX_FILE_SIZE];
+    let len = file_read(file, &mut data);
+    // line endings stay where they are
+    for line in data[..len].split_mut(|&c| c == b'\n') {
+        match line.split_last_mut() {
+            Some((b'\r', text)) => text.reverse(),
+            _ => line.reverse(),
+        }
+    }
+    if write_back {
+        file_write(file, &data[..len]);
+        ctx.out("File updated");
+    } else {
+        for line in lines(&data[..len]) {
+            ctx.out_bytes(line);
+        }
+    }
+}
+
+// `cmp <a> <b>` reports the offset of the first byte where two files differ.
+unsafe fn cmp(ctx: &mut ShellCtx, args: &[u8]) {
+    let mut args = split_args(args);
+    let (Some(a), Some(b), None) = (args.next(), args.next(), args.next()) else {
+        return ctx.err("Usage: cmp <file> <file>");
+    };
+    let dir = &DIR_STORAGE[CURRENT_DIR_IDX];
+    let (Some(file_a), Some(file_b)) = (find_file(dir, a), find_file(dir, b)) else {
+        return ctx.err("No such file");
+    };
+    let mut data_a = [0u8; MAX_FILE_SIZE];
+    let mut data_b = [0u8; MAX_FILE_SIZE];
+    let len_a = file_read(file_a, &mut data_a);
+    let len_b = file_read(file_b, &mut data_b);
+    let common = len_a.min(len_b);
+
+    let mut line = LineBuf::new();
+    if memcmp(data_a.as_ptr(), data_b.as_ptr(), common) != 0 {
+        let offset = data_a[..common].iter().zip(&data_b[..common]).position(|(x, y)| x != y).unwrap_or(0);
+        let _ = write!(line, "Files differ at offset {}", offset);
+    } else if len_a != len_b {
+        let _ = write!(line, "EOF on shorter file at offset {}", common);
+    } else {
+        let _ = write!(line, "Files are identical");
+    }
+    ctx.out(line.as_str());
+}
+
+// CRC-32 as used by zip and Ethernet (reflected, polynomial 0xEDB88320),
+// one table lookup per byte.
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &b| (crc >> 8) ^ CRC32_TABLE[((crc ^ b as u32) & 0xFF) as usize])
+}
+
+// `hashsum <file>...` prints the CRC-32 of each file, to check that a copy
+// or a reload came out byte for byte the same.
+unsafe fn hashsum(ctx: &mut ShellCtx, args: &[u8]) {
+    let mut names = split_args(args).peekable();
+    if names.peek().is_none() {
+        return ctx.err("Usage: hashsum <file>...");
+    }
+    let dir = &DIR_STORAGE[CURRENT_DIR_IDX];
+    let mut data = [0u8; MAX_FILE_SIZE];
+    for name in names {
+        let Some(file) = find_file(dir, name) else {
+            ctx.err("No such file");
+            continue;
+        };
+        let len = file_read(file, &mut data);
+        let mut line = LineBuf::new();
+        let _ = write!(line, "{:08x}  ", crc32(&data[..len]));
+        line.push_bytes(name);
+        ctx.out_bytes(line.as_bytes());
+    }
+}
+
+// `tac <file>` prints a file's lines last to first.
+unsafe fn tac(ctx: &mut ShellCtx, name: &[u8]) {
+    if name.is_empty() {
+        return ctx.err("Usage: tac <file>");
+    }
+    let Some(file) = find_file(&DIR_STORAGE[CURRENT_DIR_IDX], name) else {
+        return ctx.err("No such file");
+    };
+    let mut data = [0u8; MAX_FILE_SIZE];
+    let len = file_read(file, &mut data);
+    // an empty file has no lines, not one empty one
+    if len == 0 {
+        return;
+    }
+    for line in lines(&data[..len]).rev() {
+        ctx.out_bytes(line);
+    }
+}
+
+// `ls -l`: one entry per line with its size and modification time
+unsafe fn ls_long(ctx: &mut ShellCtx) {
+    let dir_idx = CURRENT_DIR_IDX;
+    let mut n = 0;
+    while let Some(entry) = nth_entry(&DIR_STORAGE[dir_idx], n) {
+        let mut line = LineBuf::new();
+        match entry {
+            Entry::Dir(_) => {
+                let _ = write!(line, "{:>6}  {:>7}  [", "<dir>", "");
+                line.push_bytes(entry_name(dir_idx, entry));
+                line.push_bytes(b"]");
+            }
+            Entry::File(slot) => {
+                if let Some(file) = &DIR_STORAGE[dir_idx].files[slot] {
+                    let _ = write!(line, "{:>6}  {:>6}s  ", file.len, file.mtime / TICKS_PER_SEC);
+                }
+                line.push_bytes(entry_name(dir_idx, entry));
+            }
+        }
+        ctx.out_bytes(line.as_bytes());
+        n += 1;
+    }
+}
+
+// `stat -f`: whole-filesystem statistics from a walk of the tree
+unsafe fn stat_filesystem(ctx: &mut ShellCtx) {
+    let mut bytes = 0;
+    let mut largest: Option<(usize, Entry, usize)> = None;
+    let mut deepest: Option<(usize, Entry, usize)> = None;
+    walk_tree(0, |depth, dir_idx, entry, _| {
+        if let Entry::File(slot) = entry {
+            let len = DIR_STORAGE[dir_idx].files[slot].map_or(0, |f| f.len);
+            bytes += len;
+            if largest.is_none_or(|(_, _, max)| len > max) {
+                largest = Some((dir_idx, entry, len));
+            }
+        }
+        if deepest.is_none_or(|(_, _, max)| depth > max) {
+            deepest = Some((dir_idx, entry, depth));
+        }
+    });
+
+    let (dirs, files) = (dirs_in_use(), TOTAL_FILES);
+    let mut line = LineBuf::new();
+    let _ = write!(line, "Directories:   {}", dirs);
+    ctx.out(line.as_str());
+    let mut line = LineBuf::new();
+    let _ = write!(line, "Files:         {}", files);
+    ctx.out(line.as_str());
+    let mut line = LineBuf::new();
+    let _ = write!(line, "Bytes used:    {}", bytes);
+    ctx.out(line.as_str());
+    let mut path = [0u8; WIDTH];
+    let mut line = LineBuf::new();
+    line.push_bytes(b"Largest file:  ");
+    match largest {
+        Some((dir_idx, entry, len)) => {
+            let path_len = entry_path(dir_idx, entry, &mut path);
+            line.push_bytes(&path[..path_len]);
+            let _ = write!(line, " ({} bytes)", len);
+        }
+        None => line.push_bytes(b"-"),
+    }
+    ctx.out_bytes(line.as_bytes());
+    let mut line = LineBuf::new();
+    line.push_bytes(b"Deepest path:  ");
+    match deepest {
+        Some((dir_idx, entry, _)) => {
+            let path_len = entry_path(dir_idx, entry, &mut path);
+            line.push_bytes(&path[..path_len]);
+        }
+        None => line.push_bytes(b"/"),
+    }
+    ctx.out_bytes(line.as_bytes());
+}
+
+// `du [-s]`: bytes held by the current directory's files against what its
+// MAX_FILES slots could hold, which is never more than the whole block pool;
+// -s adds every directory below it.
+unsafe fn du(ctx: &mut ShellCtx, args: &[u8]) {
+    let recursive = match args {
+        b"" => false,
+        b"-s" => true,
+        _ => return ctx.err("Usage: du [-s]"),
+    };
+    let dir_idx = CURRENT_DIR_IDX;
+    let mut used: usize = 0;
+    let mut dirs: usize = 1;
+    if recursive {
+        walk_tree(dir_idx, |_, parent, entry, _| match entry {
+            Entry::Dir(_) => dirs += 1,
+            Entry::File(slot) => used += DIR_STORAGE[parent].files[slot].map_or(0, |f| f.len),
+        });
+    } else {
+        used = DIR_STORAGE[dir_idx].files.iter().flatten().map(|f| f.len).sum();
+    }
+    let cap = (dirs * MAX_FILES * MAX_FILE_SIZE).min(NUM_BLOCKS * BLOCK_SIZE);
+    let mut line = LineBuf::new();
+    let _ = write!(line, "used {} / cap {}", used, cap);
+    if recursive {
+        let _ = write!(line, " in {} directories", dirs);
+    }
+    ctx.out(line.as_str());
+}
+
+// One-line summary of an entry: its path and size, or that it's a directory.
+unsafe fn stat_entry(ctx: &mut ShellCtx, dir_idx: usize, entry: Entry, path: &[u8]) {
+    let mut line = LineBuf::new();
+    line.push_bytes(path);
+    match entry {
+        Entry::Dir(_) => {
+            let _ = write!(line, "  directory");
+        }
+        Entry::File(slot) => {
+            if let Some(file) = &DIR_STORAGE[dir_idx].files[slot] {
+                let _ = write!(line, "  {} bytes, modified at {}s", file.len, file.mtime / TICKS_PER_SEC);
+            }
+        }
+    }
+    ctx.out_bytes(line.as_bytes());
+}
+
+// `stat <file>`: a file's size, the room it has left, the slot it occupies
+// in its directory and when it last changed, on one line.
+unsafe fn stat_file(ctx: &mut ShellCtx, name: &[u8]) {
+    if name.is_empty() {
+        return ctx.err("Usage: stat <file> | stat -f");
+    }
+    let dir = &DIR_STORAGE[CURRENT_DIR_IDX];
+    let Some(slot) = dir.files.iter().position(|f| f.is_some_and(|f| name_eq(&f.name, name))) else {
+        return ctx.err("No such file");
+    };
+    let Some(file) = &dir.files[slot] else { return };
+    let blocks = file.len.div_ceil(BLOCK_SIZE);
+    // it can grow into the rest of its last block and whatever blocks are
+    // still free, up to the size limit
+    let free = (MAX_FILE_SIZE - file.len).min(blocks * BLOCK_SIZE - file.len + FREE_BLOCKS * BLOCK_SIZE);
+    let mut line = LineBuf::new();
+    line.push_bytes(&file.name[..name_len(&file.name)]);
+    let _ = write!(
+        line,
+        "  {} bytes, {} free, {} blocks, slot {}, modified at {}s",
+        file.len,
+        free,
+        blocks,
+        slot,
+        file.mtime / TICKS_PER_SEC
+    );
+    ctx.out_bytes(line.as_bytes());
+}
+
+// --- Shell output ---
+
+const HEADER: &str = "OxOS Command Line";
+// false after `clear --no-header` until the screen is next cleared
+static HEADER_SHOWN: AtomicBool = AtomicBool::new(true);
+const TEXT_ATTR: u8 = attr(Color::White, Color::Black);
+const ERROR_ATTR: u8 = attr(Color::LightRed, Color::Black);
+
+// Somewhere normal command output can go instead of the screen. Each
+// `out` call is one line: its bytes, then a newline.
+trait Output {
+    fn write_bytes(&mut self, s: &[u8]);
+    fn newline(&mut self);
+}
+
+// In-memory buffer, e.g. to append a command's output to a file afterwards
+struct Capture {
+    buf: [u8; MAX_FILE_SIZE],
+    len: usize,
+    overflow: bool,
+}
+
+impl Output for Capture {
+    fn write_bytes(&mut self, s: &[u8]) {
+        for &c in s {
+            if self.len < self.buf.len() {
+                self.buf[self.len] = c;
+                self.len += 1;
+            } else {
+                self.overflow = true;
+            }
+        }
+    }
+
+    fn newline(&mut self) {
+        self.write_bytes(b"\n");
+    }
+}
+
+// COM1, for reading output on the host end of the serial line
+struct SerialOutput;
+
+impl Output for SerialOutput {
+    fn write_bytes(&mut self, s: &[u8]) {
+        for &c in s {
+            serial_write_byte(c);
+        }
+    }
+
+    fn newline(&mut self) {
+        serial_write_str("\r\n");
+    }
+}
+
+// State shared by the shell loop and the command handlers
+struct ShellCtx<'a> {
+    row: usize, // row the next line of output goes to
+    // while set, normal output goes here instead of the screen (for >>)
+    sink: Option<&'a mut dyn Output>,
+}
+
+impl ShellCtx<'_> {
+    // Normal command output, one line per call
+    fn out(&mut self, s: &str) {
+        self.out_bytes(s.as_bytes());
+    }
+
+    fn out_bytes(&mut self, s: &[u8]) {
+        match &mut self.sink {
+            Some(sink) => {
+                sink.write_bytes(s);
+                sink.newline();
+            }
+            None => self.write_line(s, TEXT_ATTR),
+        }
+    }
+
+    // Error output, shown in red so it stands out from normal output
+    fn err(&mut self, s: &str) {
+        self.write_line(s.as_bytes(), ERROR_ATTR);
+    }
+
+    // Lines longer than the screen follow the WRAP variable: `on` (default)
+    // continues on the next row, `word` breaks at the last space that fits
+    // and `off` cuts the line at the screen edge. The serial copy is always
+    // the whole line.
+    fn write_line(&mut self, s: &[u8], attr: u8) {
+        serial_mirror(s);
+        match var_get(b"WRAP") {
+            Some(b"off") => {
+                self.make_room(1);
+                print_bytes_at(&s[..s.len().min(WIDTH)], self.row, attr);
+                self.row += 1;
+            }
+            Some(b"word") => {
+                let mut rest = s;
+                loop {
+                    let (line, next) = if rest.len() <= WIDTH {
+                        (rest, &[][..])
+                    } else {
+                        match rest[..=WIDTH].iter().rposition(|&c| c == b' ') {
+                            Some(space) if space > 0 => (&rest[..space], &rest[space + 1..]),
+                            _ => (&rest[..WIDTH], &rest[WIDTH..]),
+                        }
+                    };
+                    self.make_room(1);
+                    print_bytes_at(line, self.row, attr);
+                    self.row += 1;
+                    if next.is_empty() {
+                        break;
+                    }
+                    rest = next;
+                }
+            }
+            _ => {
+                let rows = s.len().max(1).div_ceil(WIDTH).min(text_rows() - 1);
+                self.make_room(rows);
+                print_bytes_at(&s[..s.len().min(rows * WIDTH)], self.row, attr);
+                self.row += rows;
+            }
+        }
+    }
+
+    // Like out_bytes, but once the screen is full waits for a key and
+    // starts the next page on a cleared screen, so long output can be read
+    // a page at a time instead of scrolling past. Captured output isn't on
+    // the screen, so it is never paged.
+    fn out_paged(&mut self, s: &[u8]) {
+        if self.sink.is_none() && self.row + 1 >= text_rows() {
+            print_at("-- more --", self.row);
+            wait_key();
+            self.clear();
+        }
+        self.out_bytes(s);
+    }
+
+    // Scrolls the rows below the header up just far enough for `rows` more
+    // rows to fit above the status bar. The header stays put; after
+    // `clear --no-header` the screen is cleared instead, which brings the
+    // header back rather than pinning the first line of output in its place.
+    fn make_room(&mut self, rows: usize) {
+        if self.row + rows > text_rows() {
+            if !HEADER_SHOWN.load(Ordering::Relaxed) {
+                return self.clear();
+            }
+            let n = (self.row + rows - text_rows()).min(self.row - 1);
+            scroll_up(n);
+            self.row -= n;
+        }
+    }
+
+    fn clear(&mut self) {
+        clear_screen();
+        print_at(HEADER, 0);
+        HEADER_SHOWN.store(true, Ordering::Relaxed);
+        self.row = 1;
+    }
+}
+
+const STATUS_ATTR: u8 = attr(Color::Black, Color::LightGrey);
+
+// Turned off and on with `statusbar`; the bottom row stays reserved either way
+static STATUS_BAR: AtomicBool = AtomicBool::new(true);
+
+// Redraws the status bar on the bottom row: current path, lock key state,
+// uptime and, if set, the MARQUEE message.
+fn draw_status_bar() {
+    if !STATUS_BAR.load(Ordering::Relaxed) {
+        return print_bytes_at(&[b' '; WIDTH], height() - 1, TEXT_ATTR);
+    }
+    let mut path = [0u8; 64];
+    let path_len = dir_path(unsafe { CURRENT_DIR_IDX }, &mut path);
+    let secs = ticks() / TICKS_PER_SEC;
+
+    let mut line = LineBuf::new();
+    line.push_bytes(b" ");
+    line.push_bytes(&path[..path_len]);
+    line.push_bytes(b"  ");
+    let mut right = LineBuf::new();
+    let _ = write!(
+        right,
+        "{} {}  up {:02}:{:02}:{:02} ",
+        if CAPS_LOCK.load(Ordering::Relaxed) { "CAPS" } else { "    " },
+        if NUM_LOCK.load(Ordering::Relaxed) { "NUM" } else { "   " },
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    );
+    // the MARQUEE message scrolls through the gap between the two halves
+    let gap = WIDTH.saturating_sub(line.len + right.len + 2);
+    if let Some(message) = var_get(b"MARQUEE").filter(|m| !m.is_empty()) {
+        let cycle = message.len() + MARQUEE_SPACING;
+        let offset = (ticks() / MARQUEE_STEP_TICKS) as usize;
+        for i in 0..gap {
+            let c = message.get((offset + i) % cycle).copied().unwrap_or(b' ');
+            line.push_bytes(&[c]);
+        }
+    }
+    while line.len + right.len < WIDTH {
+        line.push_bytes(b" ");
+    }
+    line.push_bytes(right.as_bytes());
+    print_bytes_at(line.as_bytes(), height() - 1, STATUS_ATTR);
+}
+
+// The marquee moves one column per step, with blanks between repeats
+const MARQUEE_STEP_TICKS: u64 = TICKS_PER_SEC / 5;
+const MARQUEE_SPACING: usize = 4;
+
+// With CLEAR_FX=wipe, `clear` blanks the text area column by column, left to
+// right, two columns per timer tick. Anything else clears instantly.
+fn clear_transition() {
+    if var_get(b"CLEAR_FX") != Some(b"wipe") {
+        return;
+    }
+    for col in 0..WIDTH {
+        for row in 0..text_rows() {
+            put_char(row, col, b' ');
+        }
+        if col % 2 == 1 {
+            sleep_ticks(1);
+        }
+    }
+}
+
+// --- User identity ---
+
+static mut USERNAME: [u8; MAX_NAME] = [0; MAX_NAME];
+static mut USERNAME_LEN: usize = 0;
+
+unsafe fn username() -> &'static [u8] {
+    &USERNAME[..USERNAME_LEN]
+}
+
+// Asks for a user name before the shell starts. There are no passwords; an
+// empty answer logs in as "user".
+fn login(row: usize) {
+    let prompt = "login: ";
+    print_at(prompt, row);
+    let mut buf = [0u8; MAX_NAME];
+    let len = read_line(row, prompt.len(), &mut buf, None);
+    // like file names, a user name may contain spaces
+    let name: &[u8] = match buf[..len].trim_ascii() {
+        b"" => b"user",
+        name => name,
+    };
+    unsafe {
+        USERNAME = [0; MAX_NAME];
+        USERNAME[..name.len()].copy_from_slice(name);
+        USERNAME_LEN = name.len();
+    }
+}
+
+// VGA text colors by name, in attribute order
+const COLOR_NAMES: [&str; 16] = [
+    "black", "blue", "green", "cyan", "red", "magenta", "brown", "lightgrey",
+    "darkgrey", "lightblue", "lightgreen", "lightcyan", "lightred", "pink", "yellow", "white",
+];
+
+// A foreground color given by name or number (0-15), as an attribute byte
+// on a black background.
+fn parse_color(s: &[u8]) -> Option<u8> {
+    if let Some(n) = parse_u32(s).filter(|&n| n < 16) {
+        return Some(n as u8);
+    }
+    COLOR_NAMES.iter().position(|name| name.as_bytes().eq_ignore_ascii_case(s)).map(|n| n as u8)
+}
+
+// --- Shell variables ---
+
+const MAX_VARS: usize = 16;
+const MAX_VAR_VALUE: usize = 64;
+
+#[derive(Clone, Copy)]
+struct Var {
+    name: [u8; MAX_NAME],
+    name_len: usize,
+    value: [u8; MAX_VAR_VALUE],
+    value_len: usize,
+}
+
+static mut VARS: [Option<Var>; MAX_VARS] = [None; MAX_VARS];
+
+// Name/value tables shared by variables and aliases
+
+fn table_get(table: &'static [Option<Var>], name: &[u8]) -> Option<&'static [u8]> {
+    table
+        .iter()
+        .flatten()
+        .find(|v| &v.name[..v.name_len] == name)
+        .map(|v| &v.value[..v.value_len])
+}
+
+// Sets or replaces an entry. Fails if the name is too long or the table is
+// full; long values are truncated.
+fn table_set(table: &mut [Option<Var>], name: &[u8], value: &[u8]) -> bool {
+    if name.is_empty() || name.len() > MAX_NAME {
+        return false;
+    }
+    let slot = table
+        .iter()
+        .position(|v| matches!(v, Some(v) if &v.name[..v.name_len] == name))
+        .or_else(|| table.iter().position(Option::is_none));
+    let Some(slot) = slot else { return false };
+    let mut var = Var { name: [0; MAX_NAME], name_len: name.len(), value: [0; MAX_VAR_VALUE], value_len: 0 };
+    var.name[..name.len()].copy_from_slice(name);
+    var.value_len = value.len().min(MAX_VAR_VALUE);
+    var.value[..var.value_len].copy_from_slice(&value[..var.value_len]);
+    table[slot] = Some(var);
+    true
+}
+
+fn table_unset(table: &mut [Option<Var>], name: &[u8]) {
+    for v in table.iter_mut() {
+        if matches!(v, Some(var) if &var.name[..var.name_len] == name) {
+            *v = None;
+        }
+    }
+}
+
+fn table_list(ctx: &mut ShellCtx, table: &[Option<Var>]) {
+    for v in table.iter().flatten() {
+        let mut line = LineBuf::new();
+        line.push_bytes(&v.name[..v.name_len]);
+        line.push_bytes(b"=");
+        line.push_bytes(&v.value[..v.value_len]);
+        ctx.out_bytes(line.as_bytes());
+    }
+}
+
+fn var_get(name: &[u8]) -> Option<&'static [u8]> {
+    table_get(unsafe { &*core::ptr::addr_of!(VARS) }, name)
+}
+
+fn var_set(name: &[u8], value: &[u8]) -> bool {
+    table_set(unsafe { &mut *core::ptr::addr_of_mut!(VARS) }, name, value)
+}
+
+fn var_unset(name: &[u8]) {
+    table_unset(unsafe { &mut *core::ptr::addr_of_mut!(VARS) }, name);
+}
+
+// `set` lists variables, `set NAME=value` assigns one.
+fn set_command(ctx: &mut ShellCtx, args: &[u8]) {
+    if args.is_empty() {
+        return table_list(ctx, unsafe { &*core::ptr::addr_of!(VARS) });
+    }
+    let Some(eq) = args.iter().position(|&c| c == b'=') else {
+        return ctx.err("Usage: set NAME=value");
+    };
+    let (name, value) = (&args[..eq], &args[eq + 1..]);
+    if name.contains(&b' ') {
+        return ctx.err("Usage: set NAME=value");
+    }
+    if let Err(msg) = set_checked(name, value) {
+        ctx.err(msg);
+    }
+}
+
+// Sets a variable after checking the value of the ones the shell itself
+// reads, so `set` and `read` refuse the same bad values.
+fn set_checked(name: &[u8], value: &[u8]) -> Result<(), &'static str> {
+    if name == b"TIMEOUT" && parse_u32(value).is_none() {
+        return Err("TIMEOUT must be a number of ticks (0 turns it off)");
+    }
+    if name == b"WRAP" && !matches!(value, b"on" | b"off" | b"word") {
+        return Err("WRAP must be on, off or word");
+    }
+    if name.starts_with(b"PROMPT_") && parse_color(value).is_none() {
+        return Err("Unknown color (try a name like lightgreen, or 0-15)");
+    }
+    if !var_set(name, value) {
+        return Err("Can't set variable");
+    }
+    Ok(())
+}
+
+// `render <file>`: prints a file with each `${NAME}` replaced by the value
+// of that variable (empty if unset) and `$$` by a single `$`.
+unsafe fn render(ctx: &mut ShellCtx, name: &[u8]) {
+    let Some(file) = find_file(&DIR_STORAGE[CURRENT_DIR_IDX], name) else {
+        return ctx.err("No such file");
+    };
+    let mut data = [0u8; MAX_FILE_SIZE];
+    let len = file_read(file, &mut data);
+    for line in lines(&data[..len]) {
+        let mut out = [0u8; CMD_MAX];
+        let mut out_len = 0;
+        let mut push = |bytes: &[u8]| {
+            let n = bytes.len().min(out.len() - out_len);
+            out[out_len..out_len + n].copy_from_slice(&bytes[..n]);
+            out_len += n;
+        };
+        let mut i = 0;
+        while i < line.len() {
+            let rest = &line[i..];
+            if rest.starts_with(b"$$") {
+                push(b"$");
+                i += 2;
+            } else if let Some(end) = rest.strip_prefix(b"${").and_then(|r| r.iter().position(|&c| c == b'}')) {
+                push(var_get(&rest[2..2 + end]).unwrap_or(b""));
+                i += end + 3;
+            } else {
+                push(&rest[..1]);
+                i += 1;
+            }
+        }
+        ctx.out_bytes(&out[..out_len]);
+    }
+}
+
+// --- Aliases ---
+
+const MAX_ALIASES: usize = 16;
+
+static mut ALIASES: [Option<Var>; MAX_ALIASES] = [None; MAX_ALIASES];
+
+// Built-in aliases for users coming from DOS or other shells; they can be
+// redefined or removed like any other.
+const DEFAULT_ALIASES: &[(&[u8], &[u8])] = &[
+    (b"new", b"touch"),
+    (b"md", b"mkdir"),
+    (b"rd", b"rmdir"),
+    (b"del", b"rm"),
+    (b"type", b"cat"),
+    (b"ll", b"ls -l"),
+];
+
+fn init_aliases() {
+    let aliases = unsafe { &mut *core::ptr::addr_of_mut!(ALIASES) };
+    for &(name, command) in DEFAULT_ALIASES {
+        table_set(aliases, name, command);
+    }
+}
+
+// `alias` lists aliases, `alias name=command` defines one.
+fn alias_command(ctx: &mut ShellCtx, args: &[u8]) {
+    let aliases = unsafe { &mut *core::ptr::addr_of_mut!(ALIASES) };
+    if args.is_empty() {
+        return table_list(ctx, aliases);
+    }
+    match args.iter().position(|&c| c == b'=') {
+        Some(eq) if eq > 0 && !args[..eq].contains(&b' ') => {
+            if !table_set(aliases, &args[..eq], &args[eq + 1..]) {
+                ctx.err("Can't set alias");
+            }
+        }
+        _ => ctx.err("Usage: alias name=command"),
+    }
+}
+
+// Replaces the first word of `cmd` with its alias, if it has one. Only one
+// level is expanded, so an alias can't loop back into itself.
+fn expand_alias<'a>(cmd: &'a [u8], buf: &'a mut [u8]) -> &'a [u8] {
+    let word_end = cmd.iter().position(|&c| c == b' ').unwrap_or(cmd.len());
+    let aliases = unsafe { &*core::ptr::addr_of!(ALIASES) };
+    let Some(command) = table_get(aliases, &cmd[..word_end]) else { return cmd };
+    let rest = &cmd[word_end..];
+    if command.len() + rest.len() > buf.len() {
+        return cmd;
+    }
+    buf[..command.len()].copy_from_slice(command);
+    buf[command.len()..command.len() + rest.len()].copy_from_slice(rest);
+    &buf[..command.len() + rest.len()]
+}
+
+// --- Main entry point ---
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    unsafe { init_filesystem(); }
+
+    init_serial();
+    init_interrupts();
+    unsafe { init_tasks(); }
+    init_aliases();
+    init_function_keys();
+
+    #[cfg(test)]
+    test_main();
+
+    print_boot_logo();
+    sleep_ticks(TICKS_PER_SEC);
+
+    clear_screen();
+    print_at(HEADER, 0);
+    login(6);
+
+    // a recovered fault restarts the shell from this stack pointer
+    unsafe { core::arch::asm!("mov {}, rsp", out(reg) SHELL_STACK); }
+    shell(ShellCtx { row: 7, sink: None })
+}
+
+// The interactive loop: reads keys, edits the command line and runs commands.
+// Never returns; after a recovered fault it is entered again from scratch.
+fn shell(mut ctx: ShellCtx) -> ! {
+    let mut path_buf = [0u8; 64];
+    // hidden while commands run, and a fault may have left it that way
+    show_cursor(true);
+
+    // Print initial prompt
+    let prompt = build_path(unsafe { CURRENT_DIR_IDX }, &mut path_buf);
+    print_prompt(prompt, ctx.row);
+    let mut prompt_len = prompt.len();
+    set_cursor(ctx.row, prompt_len);
+
+    // The command being typed starts right after the prompt on row ctx.row and
+    // wraps onto the following rows.
+    let mut input = InputLine::new();
+    let mut status_step = u64::MAX;
+    // tick of the last Enter on an empty prompt
+    let mut last_empty_enter: Option<u64> = None;
+    // the last scancode was 0xE0, so this one is a grey (extended) key
+    let mut extended = false;
+
+    loop {
+        let scancode = pop_scancode().unwrap_or(0);
+        let was_extended = extended;
+        if scancode != 0 {
+            extended = scancode == 0xE0;
+        }
+        let shift = SHIFT_HELD.load(Ordering::Relaxed);
+        let ctrl = CTRL_HELD.load(Ordering::Relaxed);
+
+        // Only handle make codes (ignore break codes)
+        if scancode != 0 && scancode & 0x80 == 0 {
+            // a bound function key replaces the line with its command and
+            // presses Enter
+            let mut key = scancode;
+            if let Some(command) = function_key(scancode).and_then(function_key_command) {
+                input.replace(&mut ctx, prompt_len, command);
+                key = 0x1C;
+            }
+            // without the E0 prefix, 8 and 2 on the keypad are arrows only
+            // while Num Lock is off
+            let arrow = was_extended || !NUM_LOCK.load(Ordering::Relaxed);
+            match key {
+                0x0F if !ctrl => { // Tab: complete the name before the cursor
+                    let mut completion = [0u8; MAX_NAME];
+                    let n = unsafe { complete(&input.buf[..input.cursor], &mut completion) };
+                    if n > 0 {
+                        input.insert(&mut ctx, prompt_len, &completion[..n]);
+                    } else {
+                        // nothing to add: show what it could be below the
+                        // line, then start the line again under the list
+                        let line_row = ctx.row;
+                        ctx.row += input_rows(prompt_len + input.len);
+                        if unsafe { list_completions(&mut ctx, &input.buf[..input.cursor]) } {
+                            ctx.make_room(input_rows(prompt_len + input.len));
+                            let prompt = build_path(unsafe { CURRENT_DIR_IDX }, &mut path_buf);
+                            print_prompt(prompt, ctx.row);
+                            redraw_input(&mut ctx, prompt_len, &input.buf[..input.len], 0, input.len);
+                        } else {
+                            ctx.row = line_row;
+                        }
+                    }
+                }
+                0x1C if !ctrl => { // Enter
+                    input.recall = None;
+                    let mut cmd_len = input.len;
+                    let cmd_buf = &mut input.buf;
+                    // the serial transcript gets the command after its prompt
+                    for &byte in &path_buf[..prompt_len] {
+                        serial_write_byte(byte);
+                    }
+                    serial_mirror(&cmd_buf[..cmd_len]);
+                    ctx.row += input_rows(prompt_len + cmd_len);
+
+                    // two quick Enters on an empty prompt leave a blank line
+                    if cmd_len == 0 {
+                        let now = ticks();
+                        let window = var_get(b"ENTER_WINDOW").and_then(parse_u32).unwrap_or(DOUBLE_ENTER_TICKS);
+                        if last_empty_enter.is_some_and(|t| now - t <= window as u64) {
+                            ctx.make_room(1);
+                            ctx.row += 1;
+                            last_empty_enter = None;
+                        } else {
+                            last_empty_enter = Some(now);
+                        }
+                    } else {
+                        last_empty_enter = None;
+                    }
+
+                    // !!, !n and !prefix re-run a command from history
+                    if cmd_len > 1 && cmd_buf[0] == b'!' {
+                        match unsafe { history_expand(&cmd_buf[1..cmd_len]) } {
+                            Some(entry) => {
+                                cmd_buf[..entry.len()].copy_from_slice(entry);
+                                cmd_len = entry.len();
+                                ctx.out_bytes(&cmd_buf[..cmd_len]);
+                            }
+                            None => {
+                                ctx.err("No such history entry");
+                                cmd_len = 0;
+                            }
+                        }
+                    }
+                    let cmd = &cmd_buf[..cmd_len];
+
+                    if cmd_len > 0 {
+                        unsafe { history_push(cmd); }
+                    }
+
+                    show_cursor(false);
+                    start_watchdog();
+                    run_command(&mut ctx, cmd);
+                    if stop_watchdog() {
+                        ctx.err("Command timed out");
+                    }
+                    show_cursor(true);
+
+                    input.len = 0;
+                    input.cursor = 0;
+                    ctx.make_room(1);
+                    let prompt = build_path(unsafe { CURRENT_DIR_IDX }, &mut path_buf);
+                    print_prompt(prompt, ctx.row);
+                    prompt_len = prompt.len();
+                }
+                _ => input.key(&mut ctx, prompt_len, key, shift, ctrl, arrow),
+            }
+        }
+
+        if scancode != 0 {
+            let pos = prompt_len + input.cursor;
+            set_cursor(ctx.row + pos / WIDTH, pos % WIDTH);
+        }
+
+        // Refresh the status bar on every key and once a second, or at every
+        // marquee step while one is shown
+        let step = if var_get(b"MARQUEE").is_some_and(|m| !m.is_empty()) {
+            MARQUEE_STEP_TICKS
+        } else {
+            TICKS_PER_SEC
+        };
+        if scancode != 0 || ticks() / step != status_step {
+            status_step = ticks() / step;
+            draw_status_bar();
+        }
+
+        if scancode == 0 {
+            yield_now();
+            halt();
+        }
+    }
+}
+
+// The command being typed at the prompt
+struct InputLine {
+    buf: [u8; CMD_MAX],
+    len: usize,
+    cursor: usize, // offset into buf where typing goes, at most len
+    // history entry shown by Up/Down, and the line typed before browsing
+    recall: Option<usize>,
+    draft: [u8; CMD_MAX],
+    draft_len: usize,
+}
+
+impl InputLine {
+    fn new() -> Self {
+        InputLine { buf: [0; CMD_MAX], len: 0, cursor: 0, recall: None, draft: [0; CMD_MAX], draft_len: 0 }
+    }
+
+    // Applies an editing key (make code) to the line and redraws what
+    // changed. Tab and Enter are up to the shell.
+    fn key(&mut self, ctx: &mut ShellCtx, prompt_len: usize, key: u8, shift: bool, ctrl: bool, arrow: bool) {
+        match key {
+            0x0E => { // Backspace: delete the character before the cursor
+                if self.cursor > 0 {
+                    self.buf.copy_within(self.cursor..self.len, self.cursor - 1);
+                    self.len -= 1;
+                    self.cursor -= 1;
+                    redraw_input(ctx, prompt_len, &self.buf[..self.len], self.cursor, self.len + 1);
+                }
+            }
+            0x16 if ctrl => { // Ctrl+U: cut the line into the kill buffer
+                kill(&self.buf[..self.len]);
+                redraw_input(ctx, prompt_len, &[], 0, self.len);
+                self.len = 0;
+                self.cursor = 0;
+            }
+            0x25 if ctrl => { // Ctrl+K: cut from the cursor to the end
+                kill(&self.buf[self.cursor..self.len]);
+                redraw_input(ctx, prompt_len, &self.buf[..self.cursor], self.cursor, self.len);
+                self.len = self.cursor;
+            }
+            0x15 if ctrl => self.insert(ctx, prompt_len, yank()), // Ctrl+Y: paste the kill buffer
+            // other Ctrl combinations type nothing
+            _ if ctrl => {}
+            0x48 if arrow => { // Up: the previous history entry
+                let n = self.recall.unwrap_or(unsafe { HISTORY_COUNT });
+                if let Some(entry) = n.checked_sub(1).and_then(|n| unsafe { history_get(n) }) {
+                    if self.recall.is_none() {
+                        self.draft[..self.len].copy_from_slice(&self.buf[..self.len]);
+                        self.draft_len = self.len;
+                    }
+                    self.recall = Some(n - 1);
+                    self.replace(ctx, prompt_len, entry);
+                }
+            }
+            0x50 if arrow => { // Down: the next entry, then the draft again
+                if let Some(n) = self.recall {
+                    match unsafe { history_get(n + 1) } {
+                        Some(entry) => {
+                            self.recall = Some(n + 1);
+                            self.replace(ctx, prompt_len, entry);
+                        }
+                        None => {
+                            self.recall = None;
+                            let draft = self.draft;
+                            self.replace(ctx, prompt_len, &draft[..self.draft_len]);
+                        }
+                    }
+                }
+            }
+            0x4B if arrow => self.cursor = self.cursor.saturating_sub(1), // Left
+            0x4D if arrow => self.cursor = (self.cursor + 1).min(self.len), // Right
+            0x01 => { // Escape: drop the whole line, keep the prompt
+                self.recall = None;
+                redraw_input(ctx, prompt_len, &[], 0, self.len);
+                self.len = 0;
+                self.cursor = 0;
+            }
+            _ => {
+                if let Some(ascii) = typed_char(key, shift) {
+                    self.insert(ctx, prompt_len, &[ascii]);
+                }
+            }
+        }
+    }
+
+    // Replaces the line with `text`, blanking any of the old line the new
+    // one doesn't cover, and puts the cursor at the end.
+    fn replace(&mut self, ctx: &mut ShellCtx, prompt_len: usize, text: &[u8]) {
+        for pos in prompt_len..=prompt_len + self.len {
+            put_input_char(ctx.row, pos, b' ');
+        }
+        self.len = text.len().min(CMD_MAX);
+        self.buf[..self.len].copy_from_slice(&text[..self.len]);
+        self.cursor = self.len;
+        ctx.make_room(input_rows(prompt_len + self.len));
+        for (i, &c) in self.buf[..self.len].iter().enumerate() {
+            put_input_char(ctx.row, prompt_len + i, c);
+        }
+    }
+
+    // Inserts as much of `text` as fits at the cursor and moves the cursor
+    // past it.
+    fn insert(&mut self, ctx: &mut ShellCtx, prompt_len: usize, text: &[u8]) {
+        let n = text.len().min(CMD_MAX - self.len);
+        if n == 0 {
+            return;
+        }
+        self.buf.copy_within(self.cursor..self.len, self.cursor + n);
+        self.buf[self.cursor..self.cursor + n].copy_from_slice(&text[..n]);
+        self.len += n;
+        let from = self.cursor;
+        self.cursor += n;
+        redraw_input(ctx, prompt_len, &self.buf[..self.len], from, self.len);
+    }
+}
+
+// Redraws the command line from offset `from` on, blanking cells up to
+// `old_len` that a shorter line no longer covers.
+fn redraw_input(ctx: &mut ShellCtx, prompt_len: usize, line: &[u8], from: usize, old_len: usize) {
+    // the cell after the last character holds the cursor at the end of the line
+    ctx.make_room(input_rows(prompt_len + line.len()));
+    for pos in from..=line.len().max(old_len) {
+        put_input_char(ctx.row, prompt_len + pos, line.get(pos).copied().unwrap_or(b' '));
+    }
+}
+
+// Default for ENTER_WINDOW: how close together, in timer ticks, two Enters on
+// an empty prompt must be to count as a double press
+const DOUBLE_ENTER_TICKS: u32 = TICKS_PER_SEC as u32 / 3;
+
+// Longest command line the shell accepts; it may wrap over several rows
+const CMD_MAX: usize = 256;
+
+// Rows taken by a command line whose cursor sits at offset `pos` from the
+// start of the prompt
+fn input_rows(pos: usize) -> usize {
+    pos / WIDTH + 1
+}
+
+// Draws one cell of the command line, `pos` cells after the prompt start
+fn put_input_char(row: usize, pos: usize, c: u8) {
+    put_char(row + pos / WIDTH, pos % WIDTH, c);
+}
+
+// --- Kill buffer ---
+
+// The last text cut with Ctrl+U or Ctrl+K, at the prompt or in the editor,
+// for Ctrl+Y to paste. Only the first WIDTH bytes of a longer cut are kept.
+static mut KILL: [u8; WIDTH] = [0; WIDTH];
+static mut KILL_LEN: usize = 0;
+
+fn kill(text: &[u8]) {
+    let len = text.len().min(WIDTH);
+    unsafe {
+        KILL[..len].copy_from_slice(&text[..len]);
+        KILL_LEN = len;
+    }
+}
+
+fn yank() -> &'static [u8] {
+    let kill = unsafe { &*core::ptr::addr_of!(KILL) };
+    &kill[..unsafe { KILL_LEN }]
+}
+
+// --- Tab completion ---
+
+// Which names can follow a command: `cd` only takes directories, commands that
+// read or change file contents only take files.
+#[derive(Clone, Copy, PartialEq)]
+enum Completion {
+    Dirs,
+    Files,
+    Any,
+}
+
+fn completion_for(command: &[u8]) -> Completion {
+    match command {
+        b"cd" | b"rmdir" => Completion::Dirs,
+        b"cat" | b"rm" | b"edit" | b"write" | b"xxd" | b"render" | b"grep" | b"wc" | b"tac" | b"rev"
+        | b"upper" | b"lower" | b"split" | b"hashsum" | b"stat" => Completion::Files,
+        _ => Completion::Any,
+    }
+}
+
+// Calls `visit` with every name the last word of a command line could
+// complete to: a command or alias for the first word, otherwise an entry of
+// the current directory that fits the command. Returns the word's length.
+unsafe fn completions(line: &[u8], mut visit: impl FnMut(&'static [u8])) -> usize {
+    let start = line.iter().rposition(|&c| c == b' ').map_or(0, |space| space + 1);
+    let prefix = &line[start..];
+    let aliases = &*core::ptr::addr_of!(ALIASES);
+    if start == 0 {
+        let commands = COMMANDS.iter().map(|c| c.name.as_bytes());
+        let alias_names = aliases.iter().flatten().map(|a| &a.name[..a.name_len]);
+        commands.chain(alias_names).filter(|name| name.starts_with(prefix)).for_each(visit);
+        return prefix.len();
+    }
+    let command = line.split(|&c| c == b' ').next().unwrap_or_default();
+    // an alias completes like the command it stands for
+    let command = match table_get(aliases, command) {
+        Some(expanded) => expanded.split(|&c| c == b' ').next().unwrap_or_default(),
+        None => command,
+    };
+    let kind = completion_for(command);
+
+    let dir_idx = CURRENT_DIR_IDX;
+    let mut n = 0;
+    while let Some(entry) = nth_entry(&DIR_STORAGE[dir_idx], n) {
+        n += 1;
+        let wanted = match entry {
+            Entry::Dir(_) => kind != Completion::Files,
+            Entry::File(_) => kind != Completion::Dirs,
+        };
+        let name = entry_name(dir_idx, entry);
+        if wanted && name.starts_with(prefix) {
+            visit(name);
+        }
+    }
+    prefix.len()
+}
+
+// Completes the last word of a command line. Puts the characters to add in
+// `out` and returns how many there are: the rest of the name when only one
+// matches, or as much as all the matches share.
+unsafe fn complete(line: &[u8], out: &mut [u8; MAX_NAME]) -> usize {
+    let mut common: Option<&[u8]> = None;
+    let typed = completions(line, |name| {
+        common = Some(match common {
+            None => name,
+            Some(common) => &common[..common.iter().zip(name).take_while(|(a, b)| a == b).count()],
+        });
+    });
+    let Some(common) = common else { return 0 };
+    let rest = &common[typed..];
+    let n = rest.len().min(MAX_NAME);
+    out[..n].copy_from_slice(&rest[..n]);
+    n
+}
+
+// Lists the names the last word of a command line could complete to, as
+// many to a row as fit. Lists nothing, and returns false, unless there is
+// more than one.
+unsafe fn list_completions(ctx: &mut ShellCtx, line: &[u8]) -> bool {
+    const COLUMN: usize = MAX_NAME + 2;
+    let mut count = 0;
+    completions(line, |_| count += 1);
+    if count < 2 {
+        return false;
+    }
+    let mut row = LineBuf::new();
+    completions(line, |name| {
+        if row.len + COLUMN > WIDTH {
+            ctx.out_bytes(row.as_bytes());
+            row = LineBuf::new();
+        }
+        let _ = write!(row, "{:<w$}", core::str::from_utf8(name).unwrap_or("?"), w = COLUMN);
+    });
+    ctx.out_bytes(row.as_bytes().trim_ascii_end());
+    true
+}
+
+// --- Line input outside the shell loop ---
+
+// Reads a line of keyboard input at (row, col), echoing what is typed, and
+// returns its length once Enter is pressed. With a mask, that character is
+// shown in place of each typed one.
+fn read_line(row: usize, col: usize, buf: &mut [u8], mask: Option<u8>) -> usize {
+    let mut len = 0;
+    // commands run with the cursor hidden, but it belongs here while typing
+    let was_shown = cursor_shown();
+    show_cursor(true);
+    set_cursor(row, col);
+    loop {
+        let scancode = read_scancode();
+        match scancode {
+            0x2A | 0x36 | 0xAA | 0xB6 => {}
+            0x0E => {
+                if len > 0 {
+                    len -= 1;
+                    put_char(row, col + len, b' ');
+                }
+            }
+            0x1C => {
+                show_cursor(was_shown);
+                return len;
+            }
+            _ => {
+                if let Some(ascii) = typed_char(scancode, SHIFT_HELD.load(Ordering::Relaxed)) {
+                    if len < buf.len() && col + len + 1 < WIDTH {
+                        buf[len] = ascii;
+                        put_char(row, col + len, mask.unwrap_or(ascii));
+                        len += 1;
+                    }
+                }
+            }
+        }
+        set_cursor(row, col + len);
+    }
+}
+
+// Waits for any key to be pressed.
+fn wait_key() {
+    while read_scancode() & 0x80 != 0 {}
+}
+
+// Prints a yes/no question and waits for a single keypress.
+fn confirm(ctx: &mut ShellCtx, question: &[u8]) -> bool {
+    let mut line = [0u8; WIDTH];
+    let len = question.len().min(WIDTH - 8);
+    line[..len].copy_from_slice(&question[..len]);
+    line[len..len + 7].copy_from_slice(b" (y/n) ");
+    // shown even while output is being captured
+    ctx.write_line(&line[..len + 7], TEXT_ATTR);
+    let row = ctx.row - 1;
+    loop {
+        let scancode = read_scancode();
+        if scancode & 0x80 != 0 {
+            continue;
+        }
+        if let Some(answer) = scancode_to_ascii(scancode, false).filter(u8::is_ascii_alphabetic) {
+            put_char(row, len + 7, answer);
+            return answer == b'y';
+        }
+    }
+}
+
+// --- Text editor ---
+
+// `edit <file>` opens a full-screen editor on a copy of the file. Leaving it
+// with Escape keeps the session, unsaved changes included, until the next
+// `edit`; `clear` and `reset` ask before throwing those changes away.
+struct EditSession {
+    dir_idx: usize,
+    name: [u8; MAX_NAME],
+    buf: [u8; MAX_FILE_SIZE],
+    len: usize,
+    cursor: usize,
+    // first line shown on screen
+    top: usize,
+    // changed since the last save
+    dirty: bool,
+}
+
+static mut EDIT: Option<EditSession> = None;
+
+impl EditSession {
+    fn line_start(&self, pos: usize) -> usize {
+        self.buf[..pos].iter().rposition(|&c| c == b'\n').map_or(0, |i| i + 1)
+    }
+
+    fn line_end(&self, pos: usize) -> usize {
+        self.buf[pos..self.len].iter().position(|&c| c == b'\n').map_or(self.len, |i| pos + i)
+    }
+
+    fn cursor_line(&self) -> usize {
+        self.buf[..self.cursor].iter().filter(|&&c| c == b'\n').count()
+    }
+
+    fn insert(&mut self, c: u8) {
+        if self.len < self.buf.len() {
+            self.buf.copy_within(self.cursor..self.len, self.cursor + 1);
+            self.buf[self.cursor] = c;
+            self.len += 1;
+            self.cursor += 1;
+            self.dirty = true;
+        }
+    }
+
+    fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.buf.copy_within(self.cursor..self.len, self.cursor - 1);
+            self.len -= 1;
+            self.cursor -= 1;
+            self.dirty = true;
+        }
+    }
+
+    // Removes buf[start..end] into the kill buffer.
+    fn cut(&mut self, start: usize, end: usize) {
+        if start == end {
+            return;
+        }
+        kill(&self.buf[start..end]);
+        self.buf.copy_within(end..self.len, start);
+        self.len -= end - start;
+        self.cursor = start;
+        self.dirty = true;
+    }
+
+    // Moves to the previous (up) or next line, keeping the column when the
+    // line is long enough.
+    fn move_line(&mut self, up: bool) {
+        let start = self.line_start(self.cursor);
+        let col = self.cursor - start;
+        let target = if up {
+            if start == 0 {
+                return;
+            }
+            self.line_start(start - 1)
+        } else {
+            let end = self.line_end(self.cursor);
+            if end == self.len {
+                return;
+            }
+            end + 1
+        };
+        self.cursor = (target + col).min(self.line_end(target));
+    }
+
+    unsafe fn save(&mut self) -> bool {
+        let dir = &mut DIR_STORAGE[self.dir_idx];
+        let Ok(slot) = find_or_create_file(dir, &self.name) else { return false };
+        let Some(file) = dir.files[slot].as_mut() else { return false };
+        if file_write(file, &self.buf[..self.len]) < self.len {
+            return false;
+        }
+        self.dirty = false;
+        true
+    }
+
+    // Redraws the title, the visible lines and the key help on the status row.
+    fn draw(&mut self, message: &str) {
+        let rows = text_rows() - 1;
+        let line = self.cursor_line();
+        if line < self.top {
+            self.top = line;
+        } else if line >= self.top + rows {
+            self.top = line + 1 - rows;
+        }
+
+        let mut title = LineBuf::new();
+        let name_len = self.name.iter().position(|&c| c == 0).unwrap_or(MAX_NAME);
+        let _ = write!(title, " edit: ");
+        title.push_bytes(&self.name[..name_len]);
+        let _ = write!(title, "{}  {}", if self.dirty { " *" } else { "" }, message);
+        print_row(title.as_bytes(), 0, STATUS_ATTR);
+
+        let mut pos = 0;
+        for _ in 0..self.top {
+            pos = self.line_end(pos) + 1;
+        }
+        for row in 1..=rows {
+            if pos > self.len {
+                print_row(b"", row, TEXT_ATTR);
+                continue;
+            }
+            let end = self.line_end(pos);
+            print_row(&self.buf[pos..end], row, TEXT_ATTR);
+            if (pos..=end).contains(&self.cursor) {
+                // lines are cut at the screen edge, and so is the cursor
+                let col = (self.cursor - pos).min(WIDTH - 1);
+                let c = self.buf.get(self.cursor).copied().filter(|&c| c != b'\n' && self.cursor < self.len);
+                print_bytes_at_col(&[c.unwrap_or(b' ')], row, col, STATUS_ATTR);
+                set_cursor(row, col);
+            }
+            pos = end + 1;
+        }
+        print_row(b" F2 save   Esc back to the shell", text_rows(), STATUS_ATTR);
+    }
+}
+
+// Prints `s` on a whole screen row, blanking what follows it.
+fn print_row(s: &[u8], row: usize, attr: u8) {
+    let mut line = [b' '; WIDTH];
+    let len = s.len().min(WIDTH);
+    line[..len].copy_from_slice(&s[..len]);
+    print_bytes_at(&line, row, attr);
+}
+
+// Runs the editor on the current session until Escape is pressed. Keys typed
+// while `edit` was still loading the file wait in the key queue and are
+// applied here in order, with the modifiers held at the time.
+fn edit_loop(session: &mut EditSession) {
+    // the shell hides the cursor while a command runs
+    show_cursor(true);
+    session.draw("");
+    loop {
+        let mut message = "";
+        let scancode = read_scancode();
+        let shift = SHIFT_HELD.load(Ordering::Relaxed);
+        let ctrl = CTRL_HELD.load(Ordering::Relaxed);
+        match scancode {
+            0x2A | 0x36 | 0xAA | 0xB6 | 0x1D | 0x9D => continue,
+            0x16 if ctrl => session.cut(session.line_start(session.cursor), session.cursor),
+            0x25 if ctrl => {
+                // at the end of a line, cut the line break to join the next one
+                let end = session.line_end(session.cursor);
+                session.cut(session.cursor, if end == session.cursor { (end + 1).min(session.len) } else { end });
+            }
+            0x15 if ctrl => {
+                for &c in yank() {
+                    session.insert(c);
+                }
+            }
+            _ if ctrl => continue,
+            0x01 => return,
+            0x3C => {
+                message = if unsafe { session.save() } { "saved" } else { "save failed: no space" };
+            }
+            0x0E => session.backspace(),
+            0x1C => session.insert(b'\n'),
+            0x4B => session.cursor = session.cursor.saturating_sub(1),
+            0x4D => session.cursor = (session.cursor + 1).min(session.len),
+            0x48 => session.move_line(true),
+            0x50 => session.move_line(false),
+            scancode => match typed_char(scancode, shift) {
+                Some(ascii) => session.insert(ascii),
+                None => continue,
+            },
+        }
+        session.draw(message);
+    }
+}
+
+// `edit <file>` starts a session on that file in the current directory, or
+// resumes the existing one if it is for the same file; `edit` alone resumes.
+unsafe fn edit(ctx: &mut ShellCtx, name: &[u8]) {
+    let session = &mut *core::ptr::addr_of_mut!(EDIT);
+    let resume = match session {
+        Some(s) => name.is_empty() || (s.dir_idx == CURRENT_DIR_IDX && name_eq(&s.name, name)),
+        None => false,
+    };
+    if !resume {
+        if name.is_empty() {
+            return ctx.err("Usage: edit <file>");
+        }
+        if !name.ends_with(b".txt") {
+            return ctx.err("Only .txt files supported");
+        }
+        // F2 would save under a cut-short name the user never typed
+        if name.len() > MAX_NAME {
+            return ctx.err("Name too long");
+        }
+        if !confirm_discard_edits(ctx) {
+            return;
+        }
+        let mut new = EditSession {
+            dir_idx: CURRENT_DIR_IDX,
+            name: [0; MAX_NAME],
+            buf: [0; MAX_FILE_SIZE],
+            len: 0,
+            cursor: 0,
+            top: 0,
+            dirty: false,
+        };
+        new.name[..name.len()].copy_from_slice(name);
+        if let Some(file) = find_file(&DIR_STORAGE[CURRENT_DIR_IDX], name) {
+            new.len = file_read(file, &mut new.buf);
+        }
+        *session = Some(new);
+    }
+
+    let Some(s) = session.as_mut() else { return };
+    edit_loop(s);
+    ctx.clear();
+    if s.dirty {
+        ctx.out("Unsaved changes kept; type edit to go back");
+    }
+}
+
+// True when no edit session has unsaved changes, or the user agreed to drop
+// them; the session is closed in that case.
+fn confirm_discard_edits(ctx: &mut ShellCtx) -> bool {
+    let session = unsafe { &mut *core::ptr::addr_of_mut!(EDIT) };
+    if session.as_ref().is_some_and(|s| s.dirty) && !confirm(ctx, b"Discard changes?") {
+        return false;
+    }
+    *session = None;
+    true
+}
+
+// --- Manual pages ---
+
+// One entry per shell command: a one-line summary and a longer manual page
+// shown by `man`.
+struct CommandInfo {
+    name: &'static str,
+    summary: &'static str,
+    manual: &'static [&'static str],
+}
+
+const COMMANDS: &[CommandInfo] = &[
+    CommandInfo {
+        name: "echo",
+        summary: "print text",
+        manual: &[
+            "echo <text>",
+            "  Prints <text> on its own line. $((expr)) is replaced by the value",
+            "  of an integer expression using + - * / % and parentheses.",
+            "Example: echo 2 + 3 = $((2 + 3))",
+        ],
+    },
+    CommandInfo {
+        name: "clear",
+        summary: "clear the screen",
+        manual: &[
+            "clear [--header|--no-header]",
+            "  Clears the screen and redraws the title line.",
+            "  --no-header  leave the title line blank as well",
+            "  With CLEAR_FX=wipe set, the screen is wiped left to right.",
+        ],
+    },
+    CommandInfo {
+        name: "ls",
+        summary: "list the current directory",
+        manual: &[
+            "ls [-l|-1|-F]",
+            "  Lists subdirectories (in [brackets]) and then files.",
+            "  -F  mark subdirectories with a trailing / instead",
+            "  -1  one name per line",
+            "  -l  one per line with size in bytes and modification time",
+        ],
+    },
+    CommandInfo {
+        name: "mkdir",
+        summary: "create a directory",
+        manual: &[
+            "mkdir [-v] <name>",
+            "  Creates a subdirectory of the current directory. The rest of",
+            "  the line is the name, spaces and all.",
+            "  -v  report the storage index the directory was given",
+        ],
+    },
+    CommandInfo {
+        name: "rmdir",
+        summary: "remove an empty directory",
+        manual: &[
+            "rmdir <name>",
+            "  Removes a subdirectory of the current directory if it is empty.",
+            "  Files trashed from it, or left open in edit, are saved here instead.",
+        ],
+    },
+    CommandInfo {
+        name: "pwd",
+        summary: "print the current directory",
+        manual: &["pwd", "  Prints the absolute path of the current directory, e.g. /a/b."],
+    },
+    CommandInfo {
+        name: "cd",
+        summary: "change directory",
+        manual: &["cd <name> | cd ..", "  Enters a subdirectory, or goes up one level with `..`."],
+    },
+    CommandInfo {
+        name: "touch",
+        summary: "create a file or update its time",
+        manual: &[
+            "touch [-c] [-v] <file> | touch --dated <prefix>",
+            "  Creates an empty file, or updates the modification time of an",
+            "  existing one. The rest of the line is the name, spaces and all.",
+            "  -c       never create the file; do nothing if it is missing",
+            "  -v       report the directory slot a new file was given",
+            "  --dated  create <prefix>-HHMMSS.txt from the current time",
+        ],
+    },
+    CommandInfo {
+        name: "write",
+        summary: "write text to a .txt file",
+        manual: &[
+            "write <file.txt> [text] | write <file.txt> >> text",
+            "  Replaces the contents of a .txt file, creating it if needed.",
+            "  Without text the file is emptied. With >> the text is added",
+            "  to the end instead. Put a name with spaces in double quotes.",
+            "  Files hold at most 2048 bytes.",
+            "Example: write notes.txt buy milk",
+        ],
+    },
+    CommandInfo {
+        name: "cat",
+        summary: "print a file",
+        manual: &[
+            "cat [--kv] <file> [start,end]",
+            "  Prints a file, or only lines start through end (1-based).",
+            "  Either side of the range may be left out.",
+            "  --kv  line up key=value lines into two columns",
+            "Example: cat notes.txt 2,5",
+            "Any command's output can be appended to a file: cat a.txt >> b.txt",
+        ],
+    },
+    CommandInfo {
+        name: "xxd",
+        summary: "turn a hex dump back into a file",
+        manual: &[
+            "xxd -r <dump> <out>",
+            "  Reads a hex dump (xxd, hexdump -C or plain hex) and writes the",
+            "  bytes it describes to <out>. Offsets and the ASCII column are",
+            "  skipped; a * for repeated rows is refused (use hexdump -Cv).",
+        ],
+    },
+    CommandInfo {
+        name: "history",
+        summary: "list recent commands",
+        manual: &[
+            "history [-c]",
+            "  Lists the last commands typed.",
+            "  -c  forget them (same as clear-history)",
+            "  !! re-runs the last command, !3 the third listed, and !ca the",
+            "  most recent one starting with \"ca\".",
+        ],
+    },
+    CommandInfo {
+        name: "cp",
+        summary: "copy a file",
+        manual: &[
+            "cp [-f|-n] [-p] <src> <dst>",
+            "  Copies a file. You are asked before an existing <dst> is replaced.",
+            "  The copy is stamped with the current time.",
+            "  -f  overwrite without asking",
+            "  -n  never overwrite",
+            "  -p  keep the modification time of <src>",
+            "  <dst> may be a directory; with * or ? in <src> it must be one.",
+            "  Put a name with spaces in double quotes.",
+            "Example: cp *.txt backup/",
+        ],
+    },
+    CommandInfo {
+        name: "mv",
+        summary: "rename a file",
+        manual: &[
+            "mv [-f|-n] <src> <dst>",
+            "  Renames a file, or moves it into directory <dst>. You are asked",
+            "  before an existing file is replaced. <src> may use * and ?.",
+            "  Put a name with spaces in double quotes.",
+            "  -f  overwrite without asking",
+            "  -n  never overwrite",
+        ],
+    },
+    CommandInfo {
+        name: "rm",
+        summary: "delete a file",
+        manual: &[
+            "rm <file>",
+            "  Moves a file in the current directory to the trash; * and ?",
+            "  wildcards remove every match. `undo` (or",
+            "  `restore`) brings back the last one removed; `empty-trash`",
+            "  deletes them for good. The trash keeps the last 4 files; a",
+            "  wildcard matching more says how many are gone for good.",
+        ],
+    },
+    CommandInfo {
+        name: "find",
+        summary: "search for files and directories",
+        manual: &[
+            "find [name] [-type f|d] [-exec rm|cat|stat] [-f]",
+            "  Searches below the current directory and prints the full path of",
+            "  every match, or acts on it, a page at a time when the list is",
+            "  long. Says so when nothing matches.",
+            "  -type f|d   only files or only directories",
+            "  -exec rm    move matching files to the trash (asks first",
+            "              unless -f); `undo` brings back the last one",
+            "  -exec cat   print matching files",
+            "  -exec stat  show size and modification time",
+            "Example: find notes.txt -exec cat",
+        ],
+    },
+    CommandInfo {
+        name: "split",
+        summary: "cut a file into pieces",
+        manual: &[
+            "split <file> <bytes>",
+            "  Writes each <bytes>-sized chunk of <file> to <file>.0, <file>.1",
+            "  and so on; the last piece holds the remainder. Existing pieces",
+            "  are overwritten.",
+            "Example: split log.txt 512",
+        ],
+    },
+    CommandInfo {
+        name: "concat",
+        summary: "join files into one",
+        manual: &[
+            "concat <out> <file>...",
+            "  Writes the files, in order, into <out>, replacing its contents.",
+            "  Missing files are skipped with a warning; the result is cut at",
+            "  the maximum file size.",
+            "Example: concat log.txt log.txt.0 log.txt.1",
+        ],
+    },
+    CommandInfo {
+        name: "rev",
+        summary: "reverse the characters of each line",
+        manual: &[
+            "rev [-w] <file>",
+            "  Prints each line of <file> backwards.",
+            "  -w  rewrite the file instead of printing it",
+        ],
+    },
+    CommandInfo {
+        name: "cmp",
+        summary: "compare two files byte by byte",
+        manual: &[
+            "cmp <file> <file>",
+            "  Prints the offset of the first differing byte, whether one file",
+            "  is a prefix of the other, or that they are identical.",
+        ],
+    },
+    CommandInfo {
+        name: "hashsum",
+        summary: "print the CRC-32 of files",
+        manual: &[
+            "hashsum <file>...",
+            "  Prints each file's CRC-32 in hex next to its name. Equal files",
+            "  give equal sums, so this checks a cp, concat or reload.",
+        ],
+    },
+    CommandInfo {
+        name: "tac",
+        summary: "print a file's lines in reverse order",
+        manual: &["tac <file>", "  Prints the lines of <file> from the last one to the first."],
+    },
+    CommandInfo {
+        name: "log",
+        summary: "append a timestamped line to /syslog.txt",
+        manual: &[
+            "log <message>",
+            "  Appends the date and time from the RTC and <message> as one line",
+            "  of /syslog.txt, creating it if needed. When the file is full the",
+            "  oldest lines are dropped.",
+            "Example: log backup started",
+        ],
+    },
+    CommandInfo {
+        name: "upper",
+        summary: "print a file in upper case",
+        manual: &[
+            "upper [-w] <file>",
+            "  Prints <file> with ASCII letters in upper case.",
+            "  -w  rewrite the file instead of printing it",
+        ],
+    },
+    CommandInfo {
+        name: "lower",
+        summary: "print a file in lower case",
+        manual: &[
+            "lower [-w] <file>",
+            "  Prints <file> with ASCII letters in lower case.",
+            "  -w  rewrite the file instead of printing it",
+        ],
+    },
+    CommandInfo {
+        name: "wc",
+        summary: "count lines, words and bytes",
+        manual: &[
+            "wc [-l] [-w] [-c] <file>",
+            "  Prints the line, word and byte counts of <file> in columns.",
+            "  -l  lines    -w  words    -c  bytes",
+            "  Flags combine (wc -lw); with none, all three are shown.",
+        ],
+    },
+    CommandInfo {
+        name: "grep",
+        summary: "search file contents",
+        manual: &[
+            "grep <text> <file> | grep -r <text> [dir]",
+            "  Prints the lines of <file> that contain <text>.",
+            "  -r  search every file below <dir> (default: the current one),",
+            "      printing path: line for each hit and a total at the end",
+        ],
+    },
+    CommandInfo {
+        name: "locate",
+        summary: "find files by name using the index",
+        manual: &[
+            "locate <text> | locate -u",
+            "  Prints the path of every file whose name contains <text>. Uses",
+            "  an index kept up to date as files come and go, so it's faster",
+            "  than find.",
+            "  -u  rebuild the index from the directories",
+        ],
+    },
+    CommandInfo {
+        name: "audit",
+        summary: "log file changes",
+        manual: &[
+            "audit [on|off|-c]",
+            "  With the log on, every file create, write, delete and rename is",
+            "  recorded with its timer tick. Plain `audit` prints the last 32.",
+            "  -c  empty the log",
+        ],
+    },
+    CommandInfo {
+        name: "stat",
+        summary: "file or filesystem statistics",
+        manual: &[
+            "stat <file> | stat -f",
+            "  Shows a file's size, the bytes it can still grow by (less when",
+            "  the disk is nearly full), the blocks and directory slot it",
+            "  uses and when it last changed.",
+            "  -f  directories and files in use, bytes stored, the largest",
+            "      file and the deepest path in the whole filesystem",
+        ],
+    },
+    CommandInfo {
+        name: "du",
+        summary: "bytes used in this directory",
+        manual: &[
+            "du [-s]",
+            "  Adds up the sizes of the files in the current directory and",
+            "  prints them against what its file slots could hold, at most",
+            "  the 32768 bytes of the whole disk.",
+            "  -s  include every directory below it for a grand total",
+        ],
+    },
+    CommandInfo {
+        name: "tree",
+        summary: "draw the directory hierarchy",
+        manual: &[
+            "tree",
+            "  Draws every directory and file below the current directory,",
+            "  a page at a time when it doesn't fit on the screen.",
+        ],
+    },
+    CommandInfo {
+        name: "screenshot",
+        summary: "save the screen to a file",
+        manual: &["screenshot <file>", "  Saves the text currently on screen; the newest rows that fit are kept."],
+    },
+    CommandInfo {
+        name: "sleep",
+        summary: "pause for some seconds",
+        manual: &["sleep <seconds>", "  Waits; keys typed meanwhile are kept for the next command."],
+    },
+    CommandInfo {
+        name: "basename",
+        summary: "strip the directory part of a path",
+        manual: &["basename <path>", "Example: basename a/b.txt prints b.txt"],
+    },
+    CommandInfo {
+        name: "dirname",
+        summary: "strip the last part of a path",
+        manual: &["dirname <path>", "Example: dirname a/b.txt prints a"],
+    },
+    CommandInfo {
+        name: "storage",
+        summary: "map used and free storage slots",
+        manual: &[
+            "storage",
+            "  Draws a cell per directory slot, data block and, for each",
+            "  directory, file slot: solid when in use, shaded when free.",
+            "  Directories are green (the trash pink), blocks yellow and",
+            "  files cyan. The map is drawn on screen only, never captured.",
+        ],
+    },
+    CommandInfo {
+        name: "df",
+        summary: "show filesystem usage",
+        manual: &[
+            "df [-h]",
+            "  Shows how many directories, files and data blocks are in use.",
+            "  -h  show each as a bar with a percentage",
+        ],
+    },
+    CommandInfo {
+        name: "reset",
+        summary: "wipe all files and history",
+        manual: &[
+            "reset",
+            "  Removes every file, directory, history entry, variable, key",
+            "  binding and audit log entry, turns auditing off and puts the",
+            "  aliases and function keys back to the built-in ones. You stay",
+            "  logged in.",
+        ],
+    },
+    CommandInfo {
+        name: "vgamode",
+        summary: "switch between 80x25 and 80x50",
+        manual: &["vgamode 80x25|80x50", "  Changes the number of text rows on screen."],
+    },
+    CommandInfo {
+        name: "splash",
+        summary: "show the boot logo",
+        manual: &[
+            "splash",
+            "  Clears the screen and shows the boot logo with the tagline typed",
+            "  out. Any key skips the animation, then returns to the prompt.",
+        ],
+    },
+    CommandInfo {
+        name: "vmode",
+        summary: "show screen geometry and VGA state",
+        manual: &[
+            "vmode",
+            "  Prints the text mode size and font height, whether the hardware",
+            "  cursor and blinking are enabled, and the default text attribute.",
+        ],
+    },
+    CommandInfo {
+        name: "ps",
+        summary: "list running tasks",
+        manual: &["ps", "  Lists tasks with their pid, state and uptime."],
+    },
+    CommandInfo {
+        name: "spawn",
+        summary: "start a background task",
+        manual: &["spawn spinner|clock", "  Starts one of the built-in background jobs.", "  Stop it again with kill <pid>."],
+    },
+    CommandInfo {
+        name: "kill",
+        summary: "stop a task",
+        manual: &["kill <pid>", "  Stops a background task. The shell (pid 0) can't be killed."],
+    },
+    CommandInfo {
+        name: "diskinfo",
+        summary: "show the primary ATA disk",
+        manual: &["diskinfo", "  Shows model, serial number and size from ATA IDENTIFY."],
+    },
+    CommandInfo {
+        name: "memtest",
+        summary: "pattern-test a region of RAM",
+        manual: &[
+            "memtest <start> <len>",
+            "  Writes walking-ones and walking-zeros patterns over the region",
+            "  (hex addresses) and reports mismatches. Destroys its contents.",
+            "  Refused: the first MiB (VGA memory and the BIOS), the kernel",
+            "  image and its static data, and 320 KiB either side of the",
+            "  stack pointer. An unmapped address aborts the test with a",
+            "  page fault report.",
+            "Example: memtest 400000 1000",
+        ],
+    },
+    CommandInfo {
+        name: "bind",
+        summary: "remap a key",
+        manual: &[
+            "bind [<scancode> <char> [shifted char]] | bind F<n> [command]",
+            "  Makes the key with the given set 1 scancode (decimal or 0x hex)",
+            "  type <char>, or [shifted char] with Shift held (default: the",
+            "  uppercase of <char>). Without arguments, lists current bindings.",
+            "  F1-F12 run a command at the prompt instead; without a command",
+            "  the key is unbound. Defaults: F1 man, F5 !!, F10 statusbar.",
+            "Example: bind 0x10 a",
+            "Example: bind F2 ls -l",
+        ],
+    },
+    CommandInfo {
+        name: "statusbar",
+        summary: "show or hide the status bar",
+        manual: &["statusbar [on|off]", "  Without an argument, toggles the status bar on the bottom row."],
+    },
+    CommandInfo {
+        name: "alias",
+        summary: "define command shortcuts",
+        manual: &[
+            "alias [name=command] | unalias name",
+            "  Makes <name> run <command>, or lists aliases. Built in:",
+            "  new=touch md=mkdir rd=rmdir del=rm type=cat ll=ls -l",
+            "Example: alias h=history",
+        ],
+    },
+    CommandInfo {
+        name: "read",
+        summary: "read a line into a variable",
+        manual: &[
+            "read [-s] NAME",
+            "  Waits for a line of input and stores it in variable NAME. The",
+            "  values `set` refuses, e.g. WRAP=wide, are refused here too.",
+            "  -s  show * instead of the typed characters",
+        ],
+    },
+    CommandInfo {
+        name: "render",
+        summary: "print a file filling in variables",
+        manual: &[
+            "render <file>",
+            "  Prints a file with ${NAME} replaced by the variable's value",
+            "  (nothing if it is unset) and $$ by a single $.",
+            "Example: render greeting.txt",
+        ],
+    },
+    CommandInfo {
+        name: "edit",
+        summary: "edit a text file full-screen",
+        manual: &[
+            "edit [file.txt]",
+            "  Opens the file (or a new one) in a full-screen editor. Arrows",
+            "  move, F2 saves, Escape returns to the shell. Ctrl+U and Ctrl+K",
+            "  cut to the start or end of the line, Ctrl+Y pastes. Unsaved changes",
+            "  are kept until the next edit; clear and reset ask first.",
+            "  With no file, the last session is resumed.",
+        ],
+    },
+    CommandInfo {
+        name: "set",
+        summary: "set or list shell variables",
+        manual: &[
+            "set [NAME=value] | unset NAME",
+            "  Assigns a shell variable, or lists them all.",
+            "  WRAP=on|off|word  how output longer than a row is shown: wrapped",
+            "                    (default), cut at the edge, or wrapped at spaces",
+            "  CLEAR_FX=wipe     animate clear as a left-to-right wipe",
+            "  PROMPT_COLOR=c    color of the user and path in the prompt",
+            "  PROMPT_ARROW_COLOR=c  color of the prompt's \"> \"",
+            "                    (c: black blue green cyan red magenta brown",
+            "                    lightgrey darkgrey lightblue lightgreen",
+            "                    lightcyan lightred pink yellow white, or 0-15)",
+            "  ENTER_WINDOW=n    ticks (1/100 s) within which two Enters on an",
+            "                    empty prompt leave a blank line (default 33)",
+            "  MARQUEE=text      scroll text across the status bar",
+            "  TIMEOUT=n         abandon a command (sleep, memtest) after n",
+            "                    ticks; 0 or unset means no limit",
+            "Example: set WRAP=word",
+        ],
+    },
+    CommandInfo {
+        name: "bench",
+        summary: "time core kernel routines",
+        manual: &[
+            "bench",
+            "  Runs memcpy, memset, name_eq and build_path for a quarter second",
+            "  each and shows how many calls fit and the time per call. Times",
+            "  come from the 100 Hz timer, so treat them as rough.",
+        ],
+    },
+    CommandInfo {
+        name: "whoami",
+        summary: "print the user name",
+        manual: &["whoami", "  Prints the name entered at the login prompt."],
+    },
+    CommandInfo {
+        name: "man",
+        summary: "show a command's manual",
+        manual: &["man [command]", "  Shows the manual for a command, or lists all commands."],
+    },
+    CommandInfo {
+        name: "apropos",
+        summary: "search command summaries",
+        manual: &[
+            "apropos <keyword>",
+            "  Lists commands whose name or summary contains <keyword>,",
+            "  ignoring case.",
+            "Example: apropos file",
+        ],
+    },
+    CommandInfo {
+        name: "help",
+        summary: "list commands",
+        manual: &["help", "  Lists every command with a one-line summary, two to a line."],
+    },
+    CommandInfo {
+        name: "about",
+        summary: "about OxOS",
+        manual: &["about", "  Prints a short description of OxOS."],
+    },
+];
+
+fn man(ctx: &mut ShellCtx, name: &[u8]) {
+    if name.is_empty() {
+        for command in COMMANDS {
+            let mut line = LineBuf::new();
+            let _ = write!(line, "{:<12}{}", command.name, command.summary);
+            ctx.out_paged(line.as_bytes());
+        }
+        return;
+    }
+    match COMMANDS.iter().find(|c| c.name.as_bytes() == name) {
+        Some(command) => {
+            for line in command.manual {
+                ctx.out_paged(line.as_bytes());
+            }
+        }
+        None => {
+            let mut line = LineBuf::new();
+            line.push_bytes(b"No manual entry for ");
+            line.push_bytes(name);
+            ctx.err(line.as_str());
+        }
+    }
+}
+
+// `help` lists every command with its summary, two to a line where both
+// fit, so the list needs fewer pages than `man`.
+fn help(ctx: &mut ShellCtx) {
+    const COLUMN: usize = WIDTH / 2;
+    let fits = |c: &CommandInfo| 12 + c.summary.len() < COLUMN;
+    let alone = |ctx: &mut ShellCtx, c: &CommandInfo| {
+        let mut line = LineBuf::new();
+        let _ = write!(line, "{:<12}{}", c.name, c.summary);
+        ctx.out_paged(line.as_bytes());
+    };
+    // a short entry waiting for a second one to share its line
+    let mut left: Option<&CommandInfo> = None;
+    for command in COMMANDS {
+        if let Some(prev) = left.take() {
+            if !fits(command) {
+                alone(ctx, prev);
+            } else {
+                let mut line = LineBuf::new();
+                let _ = write!(line, "{:<12}{:<w$}", prev.name, prev.summary, w = COLUMN - 12);
+                let _ = write!(line, "{:<12}{}", command.name, command.summary);
+                ctx.out_paged(line.as_bytes());
+                continue;
+            }
+        }
+        if fits(command) {
+            left = Some(command);
+        } else {
+            alone(ctx, command);
+        }
+    }
+    if let Some(prev) = left {
+        alone(ctx, prev);
+    }
+    let mut line = LineBuf::new();
+    line.push_bytes(b"Aliases:");
+    for &(name, command) in DEFAULT_ALIASES {
+        line.push_bytes(b" ");
+        line.push_bytes(name);
+        line.push_bytes(b"=");
+        line.push_bytes(command);
+    }
+    ctx.out_paged(line.as_bytes());
+    ctx.out_paged(b"Type `man <command>` for details.");
+}
+
+// `apropos <keyword>` lists commands whose name or summary contains the
+// keyword, ignoring case.
+fn apropos(ctx: &mut ShellCtx, keyword: &[u8]) {
+    if keyword.is_empty() {
+        return ctx.err("Usage: apropos <keyword>");
+    }
+    let contains = |text: &str| text.as_bytes().windows(keyword.len()).any(|w| w.eq_ignore_ascii_case(keyword));
+    let mut found = false;
+    for command in COMMANDS.iter().filter(|c| contains(c.name) || contains(c.summary)) {
+        let mut line = LineBuf::new();
+        let _ = write!(line, "{:<12}{}", command.name, command.summary);
+        ctx.out_paged(line.as_bytes());
+        found = true;
+    }
+    if !found {
+        ctx.err("Nothing appropriate");
+    }
+}
+
+// Prints lines, aligning `key=value` ones so the values start in the same
+// column. Lines without '=' are printed unchanged.
+fn print_kv<'a, I: Iterator<Item = &'a [u8]>>(ctx: &mut ShellCtx, lines: impl Fn() -> I) {
+    let key_width = lines()
+        .filter_map(|line| line.iter().position(|&c| c == b'='))
+        .max()
+        .unwrap_or(0)
+        .min(WIDTH / 2);
+    for line in lines() {
+        let Some(eq) = line.iter().position(|&c| c == b'=') else {
+            ctx.out_bytes(line);
+            continue;
+        };
+        let mut out = LineBuf::new();
+        out.push_bytes(&line[..eq]);
+        while out.len < key_width {
+            out.push_bytes(b" ");
+        }
+        out.push_bytes(b" = ");
+        out.push_bytes(&line[eq + 1..]);
+        ctx.out_bytes(out.as_bytes());
+    }
+}
+
+// Filesystem usage as used/total counts, or with `-h` as a bar and a
+// percentage per resource.
+unsafe fn df(ctx: &mut ShellCtx, human: bool) {
+    let rows = [
+        ("Directories", dirs_in_use(), TRASH_DIR),
+        ("Files", TOTAL_FILES, MAX_TOTAL_FILES),
+        ("Blocks", NUM_BLOCKS - FREE_BLOCKS, NUM_BLOCKS),
+    ];
+    for (label, used, total) in rows {
+        let mut line = LineBuf::new();
+        if human {
+            const BAR: usize = 20;
+            let filled = (used * BAR).div_ceil(total);
+            let _ = write!(line, "{:<12}[", label);
+            for i in 0..BAR {
+                line.push_bytes(if i < filled { b"#" } else { b"-" });
+            }
+            let _ = write!(line, "] {:>3}%", used * 100 / total);
+        } else {
+            let _ = write!(line, "{:<12}{:>4} / {}", label, used, total);
+        }
+        ctx.out(line.as_str());
+    }
+    if !human {
+        let mut line = LineBuf::new();
+        let _ = write!(line, "({} bytes per block)", BLOCK_SIZE);
+        ctx.out(line.as_str());
+    }
+}
+
+// Cells of the storage map: CP437 full block for a slot in use, light shade
+// for a free one
+const MAP_USED: u8 = 0xDB;
+const MAP_FREE: u8 = 0xB0;
+const MAP_LABEL: usize = 14;
+const MAP_BLOCKS_PER_ROW: usize = 64;
+
+// One row of the storage map: a label, then a cell per slot. `cells` holds
+// the color of each used slot, 0 for a free one.
+fn map_row(ctx: &mut ShellCtx, label: &[u8], cells: &[u8]) {
+    ctx.make_room(1);
+    let mut text = [b' '; MAP_LABEL];
+    let n = label.len().min(MAP_LABEL - 1);
+    text[..n].copy_from_slice(&label[..n]);
+    print_bytes_at(&text, ctx.row, TEXT_ATTR);
+    for (i, &color) in cells.iter().enumerate().take(WIDTH - MAP_LABEL) {
+        let (c, attr) = if color == 0 { (MAP_FREE, 0x08) } else { (MAP_USED, color) };
+        print_bytes_at_col(&[c], ctx.row, MAP_LABEL + i, attr);
+    }
+    ctx.row += 1;
+}
+
+// `storage`: draws which DIR_STORAGE slots, data blocks and per-directory file
+// slots are taken. Directories that don't fit on the screen are counted.
+unsafe fn storage_map(ctx: &mut ShellCtx) {
+    const DIR_COLOR: u8 = attr(Color::LightGreen, Color::Black);
+    const TRASH_COLOR: u8 = attr(Color::Pink, Color::Black);
+    const BLOCK_COLOR: u8 = attr(Color::Yellow, Color::Black);
+    const FILE_COLOR: u8 = attr(Color::LightCyan, Color::Black);
+    let mut cells = [0u8; NUM_BLOCKS];
+    let block_rows = NUM_BLOCKS / MAP_BLOCKS_PER_ROW;
+    // file slots of each directory, the trash last, as far as the screen goes
+    let room = text_rows().saturating_sub(1 + block_rows + 3);
+    let total = dirs_in_use() + 1;
+    // keep the whole map on one screen
+    ctx.make_room(1 + block_rows + total.min(room + 1) + 1);
+
+    for (i, cell) in cells[..MAX_DIR_STORAGE].iter_mut().enumerate() {
+        *cell = match i {
+            TRASH_DIR => TRASH_COLOR,
+            _ if dir_in_use(i) => DIR_COLOR,
+            _ => 0,
+        };
+    }
+    map_row(ctx, b"directories", &cells[..MAX_DIR_STORAGE]);
+
+    let next = &*core::ptr::addr_of!(BLOCK_NEXT);
+    for (cell, &n) in cells.iter_mut().zip(next) {
+        *cell = if n == BLOCK_FREE { 0 } else { BLOCK_COLOR };
+    }
+    for (i, row) in cells.chunks(MAP_BLOCKS_PER_ROW).enumerate() {
+        map_row(ctx, if i == 0 { b"blocks" } else { b"" }, row);
+    }
+
+    let dirs = (0..DIR_ALLOC_INDEX).filter(|&i| dir_in_use(i));
+    for (shown, idx) in dirs.chain([TRASH_DIR]).enumerate() {
+        if shown == room {
+            let mut line = LineBuf::new();
+            let _ = write!(line, "... {} more directories", total - shown);
+            ctx.out(line.as_str());
+            break;
+        }
+        let dir = &DIR_STORAGE[idx];
+        for (cell, file) in cells.iter_mut().zip(&dir.files) {
+            *cell = if file.is_some() { FILE_COLOR } else { 0 };
+        }
+        let mut path = [0u8; WIDTH];
+        let len = dir_path(idx, &mut path);
+        map_row(ctx, if idx == TRASH_DIR { b"(trash)" } else { &path[..len] }, &cells[..MAX_FILES]);
+    }
+    ctx.out_bytes(&[MAP_USED, b' ', b'u', b's', b'e', b'd', b' ', b' ', MAP_FREE, b' ', b'f', b'r', b'e', b'e']);
+}
+
+// `<command> >> <file>`: runs the command with its output captured and
+// appends that output to the file, creating it if needed. `>> COM1` sends
+// the output out the serial port instead.
+unsafe fn append_output(ctx: &mut ShellCtx, cmd: &[u8], target: &[u8]) {
+    let target = name_arg(target);
+    if target.is_empty() {
+        return ctx.err("Usage: <command> >> <file>");
+    }
+    if target.eq_ignore_ascii_case(b"COM1") {
+        return redirect(ctx, cmd, &mut SerialOutput);
+    }
+    let mut capture = Capture { buf: [0; MAX_FILE_SIZE], len: 0, overflow: false };
+    redirect(ctx, cmd, &mut capture);
+
+    let dir = &mut DIR_STORAGE[CURRENT_DIR_IDX];
+    let slot = match find_or_create_file(dir, target) {
+        Ok(slot) => slot,
+        Err(msg) => return ctx.err(msg),
+    };
+    let Some(file) = dir.files[slot].as_mut() else { return };
+    let mut data = [0u8; MAX_FILE_SIZE];
+    let old_len = file_read(file, &mut data);
+    let n = capture.len.min(MAX_FILE_SIZE - old_len);
+    data[old_len..old_len + n].copy_from_slice(&capture.buf[..n]);
+    let written = file_write(file, &data[..old_len + n]);
+    if capture.overflow || n < capture.len || written < old_len + n {
+        ctx.err("File truncated");
+    }
+}
+
+// Runs `cmd` with its normal output sent to `sink`. Errors and prompts
+// still go to the screen.
+fn redirect(ctx: &mut ShellCtx, cmd: &[u8], sink: &mut dyn Output) {
+    let mut inner = ShellCtx { row: ctx.row, sink: Some(sink) };
+    run_command(&mut inner, cmd);
+    ctx.row = inner.row;
+}
+
+// --- Command dispatch ---
+
+// Runs one command line, printing its output below the prompt.
+fn run_command(ctx: &mut ShellCtx, cmd: &[u8]) {
+    let mut buf = [0u8; CMD_MAX + MAX_VAR_VALUE];
+    let cmd = expand_alias(cmd, &mut buf);
+    // `write <file> >> text` is write's own append form, not a redirect
+    let write_append = cmd.strip_prefix(b"write ").is_some_and(|args| split_name(args).1.starts_with(b">> "));
+    if let Some(pos) = cmd.windows(4).position(|w| w == b" >> ").filter(|_| !write_append) {
+        return unsafe { append_output(ctx, &cmd[..pos], cmd[pos + 4..].trim_ascii()) };
+    }
+    if cmd.starts_with(b"echo ") {
+        let text = &cmd[5..];
+        let mut line = LineBuf::new();
+        if !text.windows(3).any(|w| w == b"$((") {
+            // plain text may be longer than one screen line
+            ctx.out_bytes(text);
+        } else if expand_arith(text, &mut line) {
+            ctx.out_bytes(line.as_bytes());
+        } else {
+            ctx.err("Bad arithmetic expression");
+        }
+    } else if cmd == b"clear" || cmd == b"clear --header" {
+        if confirm_discard_edits(ctx) {
+            clear_transition();
+            ctx.clear();
+        }
+    } else if cmd == b"clear --no-header" {
+        if !confirm_discard_edits(ctx) {
+            return;
+        }
+        // the title comes back once output fills the screen
+        clear_transition();
+        clear_screen();
+        HEADER_SHOWN.store(false, Ordering::Relaxed);
+        ctx.row = 0;
+    } else if cmd == b"ls -l" {
+        unsafe { ls_long(ctx); }
+    } else if cmd == b"ls -1" {
+        unsafe {
+            let dir_idx = CURRENT_DIR_IDX;
+            let mut n = 0;
+            while let Some(entry) = nth_entry(&DIR_STORAGE[dir_idx], n) {
+                let mut line = LineBuf::new();
+                match entry {
+                    Entry::Dir(_) => {
+                        line.push_bytes(b"[");
+                        line.push_bytes(entry_name(dir_idx, entry));
+                        line.push_bytes(b"]");
+                    }
+                    Entry::File(_) => line.push_bytes(entry_name(dir_idx, entry)),
+                }
+                ctx.out_bytes(line.as_bytes());
+                n += 1;
+            }
         }
-
-        // Only handle make codes (ignore break codes) and avoid repeats
-        if scancode != 0 && scancode & 0x80 == 0 && scancode != last_scancode {
-            match scancode {
-                0x0E => { // Backspace
-                    if cmd_len > 0 {
-                        cmd_len -= 1;
-                        let erase_col = prompt_len + cmd_len;
-                        unsafe {
-                            *VGA_BUFFER.add((row * WIDTH + erase_col) * 2) = b' ';
-                            *VGA_BUFFER.add((row * WIDTH + erase_col) * 2 + 1) = 0x0f;
+    } else if cmd == b"ls" || cmd == b"ls -F" {
+        // -F marks directories with a trailing / instead of brackets
+        let classify = cmd == b"ls -F";
+        unsafe {
+            let dir = &DIR_STORAGE[CURRENT_DIR_IDX];
+            let mut out = [0u8; 80];
+            let mut out_len = 0;
+            for d in dir.dirs.iter() {
+                if let Some(idx) = d {
+                    let subdir = &DIR_STORAGE[*idx];
+                    let name = &subdir.name;
+                    let name_len = name_len(name);
+                    if out_len + name_len + 2 < out.len() {
+                        if !classify {
+                            out[out_len] = b'[';
+                            out_len += 1;
                         }
+                        out[out_len..out_len + name_len].copy_from_slice(&name[..name_len]);
+                        out_len += name_len;
+                        out[out_len] = if classify { b'/' } else { b']' };
+                        out_len += 1;
+                        out[out_len] = b' ';
+                        out_len += 1;
                     }
                 }
-                0x1C => { // Enter
-                    let cmd = &cmd_buf[..cmd_len];
-                    row += 1;
-
-                    if cmd.starts_with(b"echo ") {
-                        let msg = &cmd[5..];
-                        print_at(core::str::from_utf8(msg).unwrap_or(""), row);
-                        row += 1;
-                    } else if cmd == b"clear" {
-                        clear_screen();
-                        row = 1;
-                        let prompt = build_path(unsafe { CURRENT_DIR_IDX }, &mut path_buf);
-                        print_at("OxOS Command Line", 0);
-                        print_at(prompt, row);
-                        prompt_len = prompt.len();
-                        col = prompt_len;
-                    } else if cmd == b"ls" {
-                        unsafe {
-                            let dir = &DIR_STORAGE[CURRENT_DIR_IDX];
-                            let mut out = [0u8; 80];
-                            let mut out_len = 0;
-                            for d in dir.dirs.iter() {
-                                if let Some(idx) = d {
-                                    let subdir = &DIR_STORAGE[*idx];
-                                    let name = &subdir.name;
-                                    let name_len = name.iter().position(|&c| c == 0 || c == b' ').unwrap_or(MAX_NAME);
-                                    if out_len + name_len + 2 < out.len() {
-                                        out[out_len] = b'[';
-                                        out_len += 1;
-                                        out[out_len..out_len + name_len].copy_from_slice(&name[..name_len]);
-                                        out_len += name_len;
-                                        out[out_len] = b']';
-                                        out_len += 1;
-                                        out[out_len] = b' ';
-                                        out_len += 1;
-                                    }
-                                }
-                            }
-                            for f in dir.files.iter() {
-                                if let Some(ref file) = f {
-                                    let name = &file.name;
-                                    let name_len = name.iter().position(|&c| c == 0 || c == b' ').unwrap_or(MAX_NAME);
-                                    if out_len + name_len + 1 < out.len() {
-                                        out[out_len..out_len + name_len].copy_from_slice(&name[..name_len]);
-                                        out_len += name_len;
-                                        out[out_len] = b' ';
-                                        out_len += 1;
-                                    }
-                                }
-                            }
-                            print_at(core::str::from_utf8(&out[..out_len]).unwrap_or(""), row);
-                            row += 1;
-                        }
-                    } else if cmd.starts_with(b"mkdir ") {
-                        unsafe {
-                            let dir = &mut DIR_STORAGE[CURRENT_DIR_IDX];
-                            let name = &cmd[6..];
-                            let name_len = name.iter().position(|&c| c == 0 || c == b' ').unwrap_or(name.len());
-                            if let Some(new_idx) = alloc_dir() {
-                                let new_dir = &mut DIR_STORAGE[new_idx];
-                                new_dir.name = [0; MAX_NAME];
-                                new_dir.files = [None; MAX_FILES];
-                                new_dir.dirs = [None; MAX_DIRS];
-                                new_dir.parent = Some(CURRENT_DIR_IDX);
-                                new_dir.name[..name_len].copy_from_slice(&name[..name_len]);
-                                for d in dir.dirs.iter_mut() {
-                                    if d.is_none() {
-                                        *d = Some(new_idx);
-                                        print_at("Directory created", row);
-                                        row += 1;
-                                        break;
-                                    }
-                                }
-                            }
-                        }
-                    } else if cmd.starts_with(b"cd ") {
-                        unsafe {
-                            let dir = &DIR_STORAGE[CURRENT_DIR_IDX];
-                            let name = &cmd[3..];
-                            if name == b".." {
-                                if let Some(parent_idx) = dir.parent {
-                                    CURRENT_DIR_IDX = parent_idx;
-                                    print_at("Moved up", row);
-                                    row += 1;
+            }
+            for f in dir.files.iter() {
+                if let Some(ref file) = f {
+                    let name = &file.name;
+                    let name_len = name_len(name);
+                    if out_len + name_len + 1 < out.len() {
+                        out[out_len..out_len + name_len].copy_from_slice(&name[..name_len]);
+                        out_len += name_len;
+                        out[out_len] = b' ';
+                        out_len += 1;
+                    }
+                }
+            }
+            ctx.out_bytes(&out[..out_len]);
+        }
+    } else if cmd.starts_with(b"mkdir ") {
+        unsafe {
+            let dir = &mut DIR_STORAGE[CURRENT_DIR_IDX];
+            let mut name = &cmd[6..];
+            // -v: say which DIR_STORAGE index the directory got
+            let verbose = match name.strip_prefix(b"-v ") {
+                Some(rest) => {
+                    name = rest;
+                    true
+                }
+                None => false,
+            };
+            // the whole rest of the line is the name, spaces and all
+            let name = name_arg(name);
+            if name.is_empty() {
+                return ctx.err("Usage: mkdir [-v] <dir>");
+            }
+            if name.len() > MAX_NAME {
+                return ctx.err("Name too long");
+            }
+            let name_len = name.len();
+            if let Some(new_idx) = alloc_dir() {
+                let new_dir = &mut DIR_STORAGE[new_idx];
+                new_dir.name = [0; MAX_NAME];
+                new_dir.files = [None; MAX_FILES];
+                new_dir.dirs = [None; MAX_DIRS];
+                new_dir.parent = Some(CURRENT_DIR_IDX);
+                new_dir.name[..name_len].copy_from_slice(&name[..name_len]);
+                if let Some(d) = dir.dirs.iter_mut().find(|d| d.is_none()) {
+                    *d = Some(new_idx);
+                    if verbose {
+                        let mut line = LineBuf::new();
+                        let _ = write!(line, "Directory created at index {}", new_idx);
+                        ctx.out(line.as_str());
+                    } else {
+                        ctx.out("Directory created");
+                    }
+                } else {
+                    // no room in the parent; don't leak the slot
+                    free_dir(new_idx);
+                }
+            }
+        }
+    } else if cmd == b"rmdir" || cmd.starts_with(b"rmdir ") {
+        unsafe { rmdir(ctx, name_arg(cmd.get(6..).unwrap_or(&[]))); }
+    } else if cmd.starts_with(b"cd ") {
+        unsafe {
+            let dir = &DIR_STORAGE[CURRENT_DIR_IDX];
+            let name = name_arg(&cmd[3..]);
+            if name == b".." {
+                if let Some(parent_idx) = dir.parent {
+                    CURRENT_DIR_IDX = parent_idx;
+                    ctx.out("Moved up");
+                } else {
+                    ctx.err("Already at root");
+                }
+            } else if let Some(subdir_idx) = find_dir(dir, name) {
+                CURRENT_DIR_IDX = subdir_idx;
+                ctx.out("Changed directory");
+            } else {
+                ctx.err("No such directory");
+            }
+        }
+    } else if cmd.starts_with(b"touch ") {
+        unsafe {
+            let dir = &mut DIR_STORAGE[CURRENT_DIR_IDX];
+            let mut name = &cmd[6..];
+            // -c: only update an existing file, never create one
+            // -v: say which slot a new file went into
+            let mut no_create = false;
+            let mut verbose = false;
+            loop {
+                if let Some(rest) = name.strip_prefix(b"-c ") {
+                    no_create = true;
+                    name = rest;
+                } else if let Some(rest) = name.strip_prefix(b"-v ") {
+                    verbose = true;
+                    name = rest;
+                } else {
+                    break;
+                }
+            }
+            // --dated <prefix>: always creates <prefix>-HHMMSS.txt
+            let mut dated = LineBuf::new();
+            if let Some(prefix) = name.strip_prefix(b"--dated ") {
+                let (h, m, s) = rtc_time();
+                let _ = write!(dated, "-{:02}{:02}{:02}.txt", h, m, s);
+                // keep the whole time suffix; shorten the prefix to fit
+                let prefix = &prefix[..prefix.len().min(MAX_NAME - dated.len)];
+                let mut full = LineBuf::new();
+                full.push_bytes(prefix);
+                full.push_bytes(dated.as_bytes());
+                dated = full;
+                name = dated.as_bytes();
+            }
+            // the whole rest of the line is the name, spaces and all
+            let name = name_arg(name);
+            if let Some(file) = dir.files.iter_mut().flatten().find(|f| name_eq(&f.name, name)) {
+                file.mtime = ticks();
+            } else if no_create {
+                // like Unix, a missing file is not an error with -c
+            } else {
+                match create_file(dir, name) {
+                    Ok(slot) if verbose => {
+                        let mut line = LineBuf::new();
+                        let _ = write!(line, "File created in slot {}", slot);
+                        ctx.out(line.as_str());
+                    }
+                    Ok(_) => ctx.out("File created"),
+                    Err(msg) => ctx.err(msg),
+                }
+            }
+        }
+    } else if cmd.starts_with(b"write ") {
+        unsafe {
+            let dir = &mut DIR_STORAGE[CURRENT_DIR_IDX];
+            // `write a.txt` and `write a.txt ` both leave the file empty; a
+            // name with spaces goes in quotes: `write "my notes.txt" hi`
+            let (name, text) = split_name(&cmd[6..]);
+            // `write a.txt >> text` adds to the end instead of replacing
+            let (append, text) = match text.strip_prefix(b">>") {
+                Some(more) if more.is_empty() || more[0] == b' ' => (true, more.get(1..).unwrap_or_default()),
+                _ => (false, text),
+            };
+            if !name.is_empty() {
+                if name.ends_with(b".txt") {
+                    // 1. Try to find the file first
+                    let existing = dir.files.iter().position(|f| f.is_some_and(|f| name_eq(&f.name, name)));
+                    // 2. If not found, create it
+                    let file_idx = match existing {
+                        Some(i) => Ok(i),
+                        None => create_file(dir, name),
+                    };
+                    // 3. Write to the file if we have an index
+                    match file_idx {
+                        Ok(i) => {
+                            if let Some(file) = dir.files[i].as_mut() {
+                                let mut data = [0u8; MAX_FILE_SIZE];
+                                let old_len = if append { file_read(file, &mut data) } else { 0 };
+                                let n = text.len().min(MAX_FILE_SIZE - old_len);
+                                data[old_len..old_len + n].copy_from_slice(&text[..n]);
+                                if n == 0 && !text.is_empty() {
+                                    ctx.err("File full");
+                                } else if file_write(file, &data[..old_len + n]) < old_len + text.len() {
+                                    ctx.err("File truncated");
                                 } else {
-                                    print_at("Already at root", row);
-                                    row += 1;
+                                    ctx.out(if append { "Appended to file" } else { "Wrote file" });
                                 }
-                            } else if let Some(subdir_idx) = find_dir(dir, name) {
-                                CURRENT_DIR_IDX = subdir_idx;
-                                print_at("Changed directory", row);
-                                row += 1;
-                            } else {
-                                print_at("No such directory", row);
-                                row += 1;
                             }
                         }
-                    } else if cmd.starts_with(b"touch ") {
-                        unsafe {
-                            let dir = &mut DIR_STORAGE[CURRENT_DIR_IDX];
-                            let name = &cmd[6..];
-                            let name_len = name.iter().position(|&c| c == 0 || c == b' ').unwrap_or(name.len());
-                            let mut new_file = File {
-                                name: [0u8; MAX_NAME],
-                                data: [0u8; MAX_DATA],
-                                len: 0,
-                            };
-                            new_file.name[..name_len].copy_from_slice(&name[..name_len]);
-                            for f in dir.files.iter_mut() {
-                                if f.is_none() {
-                                    *f = Some(new_file);
-                                    print_at("File created", row);
-                                    row += 1;
-                                    break;
-                                }
+                        Err(msg) => ctx.err(msg),
+                    }
+                } else {
+                    ctx.err("Only .txt files supported");
+                }
+            } else {
+                ctx.err("Usage: write <file.txt> [text]");
+            }
+        }
+    } else if cmd.starts_with(b"cat ") {
+        unsafe {
+            let dir = &DIR_STORAGE[CURRENT_DIR_IDX];
+            let mut args = &cmd[4..];
+            // --kv: line up key=value pairs in two columns
+            let kv = match args.strip_prefix(b"--kv ") {
+                Some(rest) => {
+                    args = rest;
+                    true
+                }
+                None => false,
+            };
+            // a trailing range is only taken as one if it parses, so
+            // `cat my notes.txt` names a single file
+            let (name, range) = match args.iter().rposition(|&c| c == b' ') {
+                Some(space) if parse_line_range(&args[space + 1..]).is_some() => {
+                    (name_arg(&args[..space]), Some(&args[space + 1..]))
+                }
+                _ => (name_arg(args), None),
+            };
+            // Optional "start,end" range of 1-based line numbers
+            let range = match range {
+                None => Some((1, usize::MAX)),
+                Some(range) => parse_line_range(range),
+            };
+            if !name.ends_with(b".txt") {
+                ctx.err("Only .txt files supported");
+            } else if let Some((first, last)) = range {
+                if let Some(file) = find_file(dir, name) {
+                    let mut data = [0u8; MAX_FILE_SIZE];
+                    let len = file_read(file, &mut data);
+                    let selected = || lines(&data[..len]).skip(first - 1).take(last - first + 1);
+                    if kv {
+                        print_kv(ctx, selected);
+                    } else {
+                        for line in selected() {
+                            ctx.out_bytes(line);
+                        }
+                    }
+                } else {
+                    ctx.err("No such file");
+                }
+            } else {
+                ctx.err("Usage: cat [--kv] <file.txt> [start,end]");
+            }
+        }
+    } else if cmd.starts_with(b"xxd ") {
+        unsafe {
+            let dir = &mut DIR_STORAGE[CURRENT_DIR_IDX];
+            let mut args = split_args(&cmd[4..]);
+            match (args.next(), args.next(), args.next()) {
+                (Some(b"-r"), Some(src), Some(dst)) => {
+                    let mut out = [0u8; MAX_FILE_SIZE];
+                    let mut out_len = 0;
+                    let mut total = 0;
+                    let mut error = None;
+                    let mut offsets = false;
+                    if let Some(file) = find_file(dir, src) {
+                        let mut dump = [0u8; MAX_FILE_SIZE];
+                        let dump_len = file_read(file, &mut dump);
+                        for line in lines(&dump[..dump_len]) {
+                            match unhex_line(line, &mut out, &mut out_len, &mut offsets) {
+                                Ok(n) => total += n,
+                                Err(msg) => { error = Some(msg); break; }
                             }
                         }
-                    } else if cmd.starts_with(b"write ") {
-                        unsafe {
-                            let dir = &mut DIR_STORAGE[CURRENT_DIR_IDX];
-                            let rest = &cmd[6..];
-                            if let Some(space) = rest.iter().position(|&c| c == b' ') {
-                                let name = &rest[..space];
-                                let text = &rest[space+1..];
-                                if name.ends_with(b".txt") {
-                                    let name_len = name.len();
-                                    // 1. Try to find the file first
-                                    let mut file_idx = None;
-                                    for (i, f) in dir.files.iter().enumerate() {
-                                        if let Some(file) = f {
-                                            if name_eq(&file.name, name) {
-                                                file_idx = Some(i);
-                                                break;
-                                            }
-                                        }
+                        if let Some(msg) = error {
+                            ctx.err(msg);
+                        } else {
+                            match find_or_create_file(dir, dst) {
+                                Ok(i) => {
+                                    if let Some(file) = dir.files[i].as_mut() {
+                                        out_len = file_write(file, &out[..out_len]);
                                     }
-                                    // 2. If not found, create it
-                                    if file_idx.is_none() {
-                                        let mut new_file = File {
-                                            name: [0u8; MAX_NAME],
-                                            data: [0u8; MAX_DATA],
-                                            len: 0,
-                                        };
-                                        new_file.name[..name_len].copy_from_slice(name);
-                                        for (i, f) in dir.files.iter_mut().enumerate() {
-                                            if f.is_none() {
-                                                *f = Some(new_file);
-                                                file_idx = Some(i);
-                                                break;
-                                            }
-                                        }
-                                    }
-                                    // 3. Write to the file if we have an index
-                                    if let Some(i) = file_idx {
-                                        if let Some(file) = dir.files[i].as_mut() {
-                                            let write_len = text.len().min(MAX_DATA);
-                                            file.data[..write_len].copy_from_slice(&text[..write_len]);
-                                            file.len = write_len;
-                                            print_at("Wrote file", row);
-                                            row += 1;
-                                        } else {
-                                            print_at("No space for file", row);
-                                            row += 1;
-                                        }
+                                    if total > out_len {
+                                        ctx.err("Output truncated");
                                     } else {
-                                        print_at("No space for file", row);
-                                        row += 1;
+                                        ctx.out("Wrote file");
                                     }
-                                } else {
-                                    print_at("Only .txt files supported", row);
-                                    row += 1;
-                                }
-                            } else {
-                                print_at("Usage: write <file.txt> <text>", row);
-                                row += 1;
-                            }
-                        }
-                    } else if cmd.starts_with(b"cat ") {
-                        unsafe {
-                            let dir = &DIR_STORAGE[CURRENT_DIR_IDX];
-                            let name = &cmd[4..];
-                            if name.ends_with(b".txt") {
-                                if let Some(file) = find_file(dir, name) {
-                                    let s = core::str::from_utf8(&file.data[..file.len]).unwrap_or("");
-                                    print_at(s, row);
-                                    row += 1;
-                                } else {
-                                    print_at("No such file", row);
-                                    row += 1;
                                 }
-                            } else {
-                                print_at("Only .txt files supported", row);
-                                row += 1;
+                                Err(msg) => ctx.err(msg),
                             }
                         }
-                    } else if cmd == b"about" {
-                        print_at("OxOS: A hobby x86_64 OS in Rust.", row);
-                        row += 1;
-                        print_at("github.com/TacoDark/oxos", row);
-                        row += 1;
-                    } else if cmd_len > 0 {
-                        print_at("Unknown command", row);
-                        row += 1;
-                    }
-
-                    cmd_len = 0;
-                    let prompt = build_path(unsafe { CURRENT_DIR_IDX }, &mut path_buf);
-                    print_at(prompt, row);
-                    prompt_len = prompt.len();
-                    col = prompt_len;
-                }
-                _ => {
-                    if let Some(ascii) = scancode_to_ascii(scancode, shift) {
-                        if cmd_len < cmd_buf.len() {
-                            cmd_buf[cmd_len] = ascii;
-                            let draw_col = prompt_len + cmd_len;
-                            unsafe {
-                                *VGA_BUFFER.add((row * WIDTH + draw_col) * 2) = ascii;
-                                *VGA_BUFFER.add((row * WIDTH + draw_col) * 2 + 1) = 0x0f;
-                            }
-                            cmd_len += 1;
-                        }
-                        if prompt_len + cmd_len >= WIDTH {
-                            row += 1;
-                            if row >= 25 {
-                                row = 1;
-                                clear_screen();
-                                print_at("OxOS Command Line", 0);
-                            }
-                            let prompt = build_path(unsafe { CURRENT_DIR_IDX }, &mut path_buf);
-                            print_at(prompt, row);
-                            prompt_len = prompt.len();
-                            col = prompt_len;
-                            cmd_len = 0;
-                        }
+                    } else {
+                        ctx.err("No such file");
                     }
                 }
+                _ => ctx.err("Usage: xxd -r <hexfile.txt> <out.bin>"),
             }
-            last_scancode = scancode;
         }
-
-        // Always update col before drawing the cursor
-        col = prompt_len + cmd_len;
-        // Cursor blinking
-        blink_counter = blink_counter.wrapping_add(1);
-        if blink_counter % 1_000_000 < 500_000 {
-            unsafe {
-                *VGA_BUFFER.add((row * WIDTH + col) * 2) = b'_';
-                *VGA_BUFFER.add((row * WIDTH + col) * 2 + 1) = 0x0f;
+    } else if cmd == b"history" {
+        unsafe {
+            let mut n = 0;
+            while let Some(entry) = history_get(n) {
+                let mut line = [b' '; HISTORY_WIDTH + 4];
+                line[1] = b'1' + n as u8;
+                let len = entry.len().min(line.len() - 4);
+                line[4..4 + len].copy_from_slice(&entry[..len]);
+                ctx.out_bytes(&line[..4 + len]);
+                n += 1;
+            }
+        }
+    } else if cmd == b"clear-history" || cmd == b"history -c" {
+        unsafe { history_clear(); }
+        ctx.out("History cleared");
+    } else if cmd.starts_with(b"cp ") {
+        unsafe { copy_or_move(ctx, &cmd[3..], false); }
+    } else if cmd.starts_with(b"mv ") {
+        unsafe { copy_or_move(ctx, &cmd[3..], true); }
+    } else if cmd == b"edit" || cmd.starts_with(b"edit ") {
+        unsafe { edit(ctx, name_arg(cmd.get(5..).unwrap_or(&[]))); }
+    } else if cmd.starts_with(b"screenshot") && (cmd.len() == 10 || cmd[10] == b' ') {
+        unsafe { screenshot(ctx, name_arg(&cmd[10..])); }
+    } else if cmd.starts_with(b"sleep ") {
+        match parse_u32(&cmd[6..]) {
+            Some(secs) => sleep_ticks(secs as u64 * TICKS_PER_SEC),
+            None => ctx.err("Usage: sleep <seconds>"),
+        }
+    } else if cmd.starts_with(b"basename ") {
+        ctx.out_bytes(basename(&cmd[9..]));
+    } else if cmd.starts_with(b"dirname ") {
+        ctx.out_bytes(dirname(&cmd[8..]));
+    } else if cmd == b"df" || cmd == b"df -h" {
+        unsafe { df(ctx, cmd == b"df -h"); }
+    } else if cmd == b"storage" {
+        unsafe { storage_map(ctx); }
+    } else if cmd == b"reset" {
+        if !confirm_discard_edits(ctx) {
+            return;
+        }
+        // Soft restart: the filesystem, history, variables, key bindings and
+        // audit log are wiped, the aliases and function keys go back to the
+        // defaults and auditing is off; the logged-in user name is kept.
+        unsafe {
+            init_filesystem();
+            history_clear();
+            *core::ptr::addr_of_mut!(VARS) = [None; MAX_VARS];
+            *core::ptr::addr_of_mut!(ALIASES) = [None; MAX_ALIASES];
+            *core::ptr::addr_of_mut!(BINDINGS) = [None; MAX_BINDINGS];
+            *core::ptr::addr_of_mut!(FUNCTION_KEYS) = [None; MAX_FUNCTION_KEYS];
+            *core::ptr::addr_of_mut!(AUDIT_LOG) = [None; AUDIT_SIZE];
+            AUDIT_NEXT = 0;
+            AUDIT_ENABLED = false;
+        }
+        init_aliases();
+        init_function_keys();
+        clear_screen();
+        print_boot_logo();
+        sleep_ticks(TICKS_PER_SEC);
+        ctx.clear();
+    } else if cmd.starts_with(b"vgamode ") {
+        match &cmd[8..] {
+            b"80x25" | b"80x50" => {
+                set_text_rows(if cmd[11..] == *b"50" { MAX_HEIGHT } else { DEFAULT_HEIGHT });
+                ctx.clear();
             }
+            _ => ctx.err("Usage: vgamode 80x25|80x50"),
+        }
+    } else if cmd == b"tree" {
+        unsafe { tree(ctx); }
+    } else if cmd == b"ps" {
+        unsafe { ps(ctx); }
+    } else if cmd.starts_with(b"spawn ") {
+        let name = &cmd[6..];
+        match BACKGROUND_JOBS.iter().find(|(job, _)| job.as_bytes() == name) {
+            Some(&(job, entry)) => match unsafe { spawn_task(job, entry) } {
+                Some(pid) => {
+                    let mut line = LineBuf::new();
+                    let _ = write!(line, "Started {} as task {}", job, pid);
+                    ctx.out(line.as_str());
+                }
+                None => ctx.err("Too many tasks"),
+            },
+            None => ctx.err("Usage: spawn spinner|clock"),
+        }
+    } else if cmd.starts_with(b"kill ") {
+        let pid = parse_u32(&cmd[5..]).map_or(usize::MAX, |pid| pid as usize);
+        if unsafe { kill_task(pid) } {
+            ctx.out("Task stopped");
         } else {
-            unsafe {
-                *VGA_BUFFER.add((row * WIDTH + col) * 2) = b' ';
-                *VGA_BUFFER.add((row * WIDTH + col) * 2 + 1) = 0x0f;
+            ctx.err("No such task");
+        }
+    } else if cmd == b"statusbar" || cmd.starts_with(b"statusbar ") {
+        match cmd.get(10..).unwrap_or(&[]).trim_ascii() {
+            b"" => { STATUS_BAR.fetch_xor(true, Ordering::Relaxed); }
+            b"on" => STATUS_BAR.store(true, Ordering::Relaxed),
+            b"off" => STATUS_BAR.store(false, Ordering::Relaxed),
+            _ => ctx.err("Usage: statusbar [on|off]"),
+        }
+    } else if cmd == b"splash" {
+        splash(ctx);
+    } else if cmd == b"vmode" {
+        vmode(ctx);
+    } else if cmd == b"diskinfo" {
+        diskinfo(ctx);
+    } else if cmd == b"bench" {
+        bench(ctx);
+    } else if cmd == b"memtest" || cmd.starts_with(b"memtest ") {
+        memtest(ctx, cmd.get(8..).unwrap_or(&[]));
+    } else if cmd == b"find" || cmd.starts_with(b"find ") {
+        unsafe { find(ctx, cmd.get(5..).unwrap_or(&[])); }
+    } else if cmd == b"stat -f" {
+        unsafe { stat_filesystem(ctx); }
+    } else if cmd == b"stat" || cmd.starts_with(b"stat ") {
+        unsafe { stat_file(ctx, name_arg(cmd.get(5..).unwrap_or(&[]))); }
+    } else if cmd == b"du" || cmd.starts_with(b"du ") {
+        unsafe { du(ctx, cmd.get(3..).unwrap_or(&[]).trim_ascii()); }
+    } else if cmd == b"audit" || cmd.starts_with(b"audit ") {
+        unsafe { audit_command(ctx, cmd.get(6..).unwrap_or(&[]).trim_ascii()); }
+    } else if cmd == b"split" || cmd.starts_with(b"split ") {
+        unsafe { split(ctx, cmd.get(6..).unwrap_or(&[])); }
+    } else if cmd == b"concat" || cmd.starts_with(b"concat ") {
+        unsafe { concat(ctx, cmd.get(7..).unwrap_or(&[])); }
+    } else if cmd == b"rev" || cmd.starts_with(b"rev ") {
+        unsafe { rev(ctx, cmd.get(4..).unwrap_or(&[])); }
+    } else if cmd == b"cmp" || cmd.starts_with(b"cmp ") {
+        unsafe { cmp(ctx, cmd.get(4..).unwrap_or(&[])); }
+    } else if cmd == b"hashsum" || cmd.starts_with(b"hashsum ") {
+        unsafe { hashsum(ctx, cmd.get(8..).unwrap_or(&[])); }
+    } else if cmd == b"tac" || cmd.starts_with(b"tac ") {
+        unsafe { tac(ctx, name_arg(cmd.get(4..).unwrap_or(&[]))); }
+    } else if cmd == b"log" || cmd.starts_with(b"log ") {
+        unsafe { log_message(ctx, cmd.get(4..).unwrap_or(&[]).trim_ascii()); }
+    } else if cmd == b"upper" || cmd.starts_with(b"upper ") {
+        unsafe { change_case(ctx, cmd.get(6..).unwrap_or(&[]), true); }
+    } else if cmd == b"lower" || cmd.starts_with(b"lower ") {
+        unsafe { change_case(ctx, cmd.get(6..).unwrap_or(&[]), false); }
+    } else if cmd == b"wc" || cmd.starts_with(b"wc ") {
+        unsafe { wc(ctx, cmd.get(3..).unwrap_or(&[])); }
+    } else if cmd == b"grep" || cmd.starts_with(b"grep ") {
+        unsafe { grep(ctx, cmd.get(5..).unwrap_or(&[])); }
+    } else if cmd == b"locate" || cmd.starts_with(b"locate ") {
+        unsafe { locate(ctx, cmd.get(7..).unwrap_or(&[]).trim_ascii()); }
+    } else if cmd.starts_with(b"rm ") {
+        unsafe {
+            let dir = &mut DIR_STORAGE[CURRENT_DIR_IDX];
+            let name = name_arg(&cmd[3..]);
+            if is_glob(name) {
+                let mut count = 0;
+                for slot in 0..MAX_FILES {
+                    let matched = match &DIR_STORAGE[CURRENT_DIR_IDX].files[slot] {
+                        Some(file) => glob_match(name, &file.name[..name_len(&file.name)]),
+                        None => false,
+                    };
+                    if matched {
+                        trash_file(CURRENT_DIR_IDX, slot);
+                        count += 1;
+                    }
+                }
+                let mut line = LineBuf::new();
+                let _ = write!(line, "{} file(s) moved to trash", count);
+                ctx.out(line.as_str());
+                if count > TRASH_SIZE {
+                    report_trash_overflow(ctx, count);
+                }
+                return;
+            }
+            match dir.files.iter().position(|f| matches!(f, Some(file) if name_eq(&file.name, name))) {
+                Some(slot) => {
+                    trash_file(CURRENT_DIR_IDX, slot);
+                    ctx.out("File moved to trash (undo restores it)");
+                }
+                None => ctx.err("No such file"),
             }
         }
-
-        unsafe { core::arch::asm!("pause"); }
+    } else if cmd == b"undo" || cmd == b"restore" {
+        unsafe { undo_remove(ctx); }
+    } else if cmd == b"empty-trash" {
+        unsafe { empty_trash(); }
+        ctx.out("Trash emptied");
+    } else if cmd == b"apropos" || cmd.starts_with(b"apropos ") {
+        apropos(ctx, cmd.get(8..).unwrap_or(&[]).trim_ascii());
+    } else if cmd == b"help" {
+        help(ctx);
+    } else if cmd == b"man" || cmd.starts_with(b"man ") {
+        man(ctx, cmd.get(4..).unwrap_or(&[]).trim_ascii());
+    } else if cmd == b"bind" || cmd.starts_with(b"bind ") {
+        bind(ctx, cmd.get(5..).unwrap_or(&[]));
+    } else if cmd == b"alias" || cmd.starts_with(b"alias ") {
+        alias_command(ctx, cmd.get(6..).unwrap_or(&[]).trim_ascii());
+    } else if let Some(name) = cmd.strip_prefix(b"unalias ") {
+        table_unset(unsafe { &mut *core::ptr::addr_of_mut!(ALIASES) }, name.trim_ascii());
+    } else if cmd.starts_with(b"render ") {
+        unsafe { render(ctx, name_arg(&cmd[7..])); }
+    } else if cmd.starts_with(b"read ") {
+        // read [-s] NAME: a line of input into a variable, -s masks it
+        let args = cmd[5..].trim_ascii();
+        let (mask, name) = match args.strip_prefix(b"-s ") {
+            Some(name) => (Some(b'*'), name.trim_ascii()),
+            None => (None, args),
+        };
+        if name.is_empty() || name.contains(&b' ') || name.len() > MAX_NAME {
+            ctx.err("Usage: read [-s] NAME");
+        } else {
+            let mut value = [0u8; MAX_VAR_VALUE];
+            ctx.make_room(1);
+            let len = read_line(ctx.row, 0, &mut value, mask);
+            ctx.row += 1;
+            if let Err(msg) = set_checked(name, &value[..len]) {
+                ctx.err(msg);
+            }
+        }
+    } else if cmd == b"set" || cmd.starts_with(b"set ") {
+        set_command(ctx, cmd.get(4..).unwrap_or(&[]).trim_ascii());
+    } else if let Some(name) = cmd.strip_prefix(b"unset ") {
+        var_unset(name.trim_ascii());
+    } else if cmd == b"pwd" {
+        // room for the deepest path dir_path builds
+        let mut path = [0u8; 8 * (MAX_NAME + 1)];
+        let len = dir_path(unsafe { CURRENT_DIR_IDX }, &mut path);
+        ctx.out_bytes(&path[..len]);
+    } else if cmd == b"whoami" {
+        ctx.out_bytes(unsafe { username() });
+    } else if cmd == b"about" {
+        ctx.out("OxOS: A hobby x86_64 OS in Rust.");
+        ctx.out("github.com/TacoDark/oxos");
+    } else if !cmd.is_empty() {
+        ctx.err("Unknown command");
     }
 }
 
 // --- Keyboard scancode to ASCII ---
 
+// Runtime overrides of the keymap set with `bind`, checked before the default
+// tables: (scancode, character, shifted character).
+const MAX_BINDINGS: usize = 8;
+static mut BINDINGS: [Option<(u8, u8, u8)>; MAX_BINDINGS] = [None; MAX_BINDINGS];
+
+// `bind <scancode> <char> [shifted char]` remaps a key; the shifted character
+// defaults to the uppercase of <char>. `bind F<n> [command]` sets what a
+// function key runs. Plain `bind` lists both kinds of binding.
+fn bind(ctx: &mut ShellCtx, line: &[u8]) {
+    let bindings = unsafe { &mut *core::ptr::addr_of_mut!(BINDINGS) };
+    let mut args = line.split(|&c| c == b' ').filter(|a| !a.is_empty());
+    let Some(code) = args.next() else {
+        for &(scancode, c, shifted) in bindings.iter().flatten() {
+            let mut line = LineBuf::new();
+            let _ = write!(line, "{:#04x} -> {} {}", scancode, c as char, shifted as char);
+            ctx.out(line.as_str());
+        }
+        return table_list(ctx, unsafe { &*core::ptr::addr_of!(FUNCTION_KEYS) });
+    };
+    if matches!(code, [b'F' | b'f', _, ..]) {
+        // the command is everything after the key name, spaces included
+        let command = line.trim_ascii()[code.len()..].trim_ascii();
+        return bind_function_key(ctx, code, command);
+    }
+    let scancode = if code.starts_with(b"0x") { parse_hex(code) } else { parse_u32(code) };
+    let (scancode, c, shifted) = match (scancode, args.next(), args.next(), args.next()) {
+        (Some(sc), Some(&[c]), shifted, None) if sc < 0x80 && c.is_ascii_graphic() => match shifted {
+            None => (sc as u8, c, c.to_ascii_uppercase()),
+            Some(&[s]) if s.is_ascii_graphic() => (sc as u8, c, s),
+            _ => return ctx.err("Usage: bind <scancode> <char> [shifted char]"),
+        },
+        _ => return ctx.err("Usage: bind <scancode> <char> [shifted char]"),
+    };
+    let slot = bindings
+        .iter()
+        .position(|b| matches!(b, Some((code, _, _)) if *code == scancode))
+        .or_else(|| bindings.iter().position(Option::is_none));
+    match slot {
+        Some(slot) => {
+            bindings[slot] = Some((scancode, c, shifted));
+            ctx.out("Key bound");
+        }
+        None => ctx.err("Too many bindings"),
+    }
+}
+
+// F1-F10 are scancodes 0x3B-0x44, F11 and F12 are 0x57 and 0x58. Returns
+// the key's number.
+fn function_key(scancode: u8) -> Option<u8> {
+    match scancode {
+        0x3B..=0x44 => Some(scancode - 0x3B + 1),
+        0x57 | 0x58 => Some(scancode - 0x57 + 11),
+        _ => None,
+    }
+}
+
+// Commands run by function keys at the prompt, stored under "F1".."F12"
+const MAX_FUNCTION_KEYS: usize = 12;
+static mut FUNCTION_KEYS: [Option<Var>; MAX_FUNCTION_KEYS] = [None; MAX_FUNCTION_KEYS];
+
+const DEFAULT_FUNCTION_KEYS: &[(&[u8], &[u8])] = &[
+    (b"F1", b"man"),
+    (b"F5", b"!!"),
+    (b"F10", b"statusbar"),
+];
+
+fn init_function_keys() {
+    let keys = unsafe { &mut *core::ptr::addr_of_mut!(FUNCTION_KEYS) };
+    for &(name, command) in DEFAULT_FUNCTION_KEYS {
+        table_set(keys, name, command);
+    }
+}
+
+fn function_key_command(n: u8) -> Option<&'static [u8]> {
+    let mut name = LineBuf::new();
+    let _ = write!(name, "F{}", n);
+    table_get(unsafe { &*core::ptr::addr_of!(FUNCTION_KEYS) }, name.as_bytes())
+}
+
+// `bind F<n> [command]` sets or, without a command, clears what a function
+// key runs.
+fn bind_function_key(ctx: &mut ShellCtx, key: &[u8], command: &[u8]) {
+    let n = parse_u32(&key[1..]).filter(|n| (1..=MAX_FUNCTION_KEYS as u32).contains(n));
+    let Some(n) = n else { return ctx.err("No such function key") };
+    let mut name = LineBuf::new();
+    let _ = write!(name, "F{}", n);
+    let keys = unsafe { &mut *core::ptr::addr_of_mut!(FUNCTION_KEYS) };
+    if command.is_empty() {
+        table_unset(keys, name.as_bytes());
+        ctx.out("Key unbound");
+    } else if table_set(keys, name.as_bytes(), command) {
+        ctx.out("Key bound");
+    } else {
+        ctx.err("Command too long");
+    }
+}
+
 fn scancode_to_ascii(scancode: u8, shift: bool) -> Option<u8> {
+    let bindings = unsafe { &*core::ptr::addr_of!(BINDINGS) };
+    if let Some(&(_, c, shifted)) = bindings.iter().flatten().find(|b| b.0 == scancode) {
+        return Some(if shift { shifted } else { c });
+    }
     // US QWERTY scancode set 1
     let normal = [
         0, 0, b'1', b'2', b'3', b'4', b'5', b'6', b'7', b'8', b'9', b'0', b'-', b'=', 0, 0,
@@ -502,6 +6147,7 @@ fn scancode_to_ascii(scancode: u8, shift: bool) -> Option<u8> {
 
 // --- Panic handler ---
 
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
     loop {}
@@ -520,17 +6166,47 @@ fn print_boot_logo() {
         "",
     ];
     for (i, line) in logo.iter().enumerate() {
-        print_at(line, i);
+        print_at_colored(line, i, Color::LightCyan, Color::Black);
+    }
+}
+
+const TAGLINE: &str = "A hobby operating system written in Rust";
+
+// Prints `s` at (row, col) one character per timer tick. A key press skips
+// to the end, and is swallowed; returns false in that case.
+fn type_out(s: &str, row: usize, col: usize) -> bool {
+    for (i, &c) in s.as_bytes().iter().enumerate() {
+        if pop_scancode().is_some_and(|sc| sc & 0x80 == 0) {
+            print_bytes_at_col(&s.as_bytes()[i..], row, col + i, TEXT_ATTR);
+            return false;
+        }
+        print_bytes_at_col(&[c], row, col + i, TEXT_ATTR);
+        sleep_ticks(1);
+    }
+    true
+}
+
+// `splash`: the boot logo again, with the tagline typed out, until a key is
+// pressed.
+fn splash(ctx: &mut ShellCtx) {
+    clear_screen();
+    print_boot_logo();
+    if type_out(TAGLINE, 7, 0) {
+        print_at("Press any key", 9);
+        wait_key();
     }
+    ctx.clear();
 }
 
-fn build_path(mut idx: usize, buf: &mut [u8]) -> &str {
+// Writes the absolute path of a directory ("/" or "/a/b") into `buf` and
+// returns its length.
+fn dir_path(mut idx: usize, buf: &mut [u8]) -> usize {
     let mut parts = [[0u8; MAX_NAME]; 8];
     let mut depth = 0;
     unsafe {
         while idx != 0 && depth < parts.len() {
             let dir = &DIR_STORAGE[idx];
-            let name_len = dir.name.iter().position(|&c| c == 0 || c == b' ').unwrap_or(MAX_NAME);
+            let name_len = name_len(&dir.name);
             parts[depth][..name_len].copy_from_slice(&dir.name[..name_len]);
             depth += 1;
             idx = dir.parent.unwrap_or(0);
@@ -541,7 +6217,7 @@ fn build_path(mut idx: usize, buf: &mut [u8]) -> &str {
     pos += 1;
     for i in (0..depth).rev() {
         let name = &parts[i];
-        let name_len = name.iter().position(|&c| c == 0 || c == b' ').unwrap_or(MAX_NAME);
+        let name_len = name_len(name);
         if name_len > 0 {
             if pos + name_len < buf.len() {
                 buf[pos..pos + name_len].copy_from_slice(&name[..name_len]);
@@ -552,6 +6228,18 @@ fn build_path(mut idx: usize, buf: &mut [u8]) -> &str {
         }
     }
     if pos > 1 { pos -= 1; } // Remove trailing slash unless root
+    pos
+}
+
+fn build_path(idx: usize, buf: &mut [u8]) -> &str {
+    let mut pos = 0;
+    let user = unsafe { username() };
+    if !user.is_empty() && user.len() + 1 < buf.len() {
+        buf[..user.len()].copy_from_slice(user);
+        buf[user.len()] = b':';
+        pos = user.len() + 1;
+    }
+    pos += dir_path(idx, &mut buf[pos..]);
     let prompt = b"> ";
     if pos + prompt.len() < buf.len() {
         buf[pos..pos + prompt.len()].copy_from_slice(prompt);
@@ -559,3 +6247,470 @@ fn build_path(mut idx: usize, buf: &mut [u8]) -> &str {
     }
     core::str::from_utf8(&buf[..pos]).unwrap_or("> ")
 }
+
+// Draws a prompt from build_path: the user and path in PROMPT_COLOR and the
+// trailing "> " in PROMPT_ARROW_COLOR, both white unless set.
+fn print_prompt(prompt: &str, row: usize) {
+    let color = |var: &[u8]| var_get(var).and_then(parse_color).unwrap_or(TEXT_ATTR);
+    let bytes = prompt.as_bytes();
+    let split = if bytes.ends_with(b"> ") { bytes.len() - 2 } else { bytes.len() };
+    print_bytes_at(&bytes[..split], row, color(b"PROMPT_COLOR"));
+    print_bytes_at_col(&bytes[split..], row, split, color(b"PROMPT_ARROW_COLOR"));
+}
+
+// --- Tests ---
+
+// `cargo test` boots the kernel in QEMU and runs every #[test_case] once the
+// hardware is set up, before login. Results go to COM1 and the outcome leaves
+// QEMU through its isa-debug-exit device (see Cargo.toml).
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXIT_PORT: u16 = 0xF4;
+
+    // QEMU exits with (code << 1) | 1: 33 for success, 35 for failure
+    fn exit_qemu(success: bool) -> ! {
+        let code: u32 = if success { 0x10 } else { 0x11 };
+        unsafe {
+            core::arch::asm!("out dx, eax", in("dx") EXIT_PORT, in("eax") code);
+        }
+        loop {
+            halt();
+        }
+    }
+
+    pub trait Testable {
+        fn run(&self);
+    }
+
+    impl<T: Fn()> Testable for T {
+        fn run(&self) {
+            serial_write_str(core::any::type_name::<T>());
+            serial_write_str("... ");
+            self();
+            serial_write_str("ok\r\n");
+        }
+    }
+
+    pub fn run(tests: &[&dyn Testable]) {
+        let mut line = LineBuf::new();
+        let _ = write!(line, "running {} tests", tests.len());
+        serial_mirror(line.as_bytes());
+        for test in tests {
+            test.run();
+        }
+        exit_qemu(true);
+    }
+
+    #[panic_handler]
+    fn panic(info: &PanicInfo) -> ! {
+        serial_write_str("FAILED\r\n");
+        let mut line = LineBuf::new();
+        let _ = write!(line, "{}", info);
+        serial_mirror(line.as_bytes());
+        exit_qemu(false);
+    }
+
+    // Byte at a time, the way memset and memcpy used to work
+    fn reference_set(s: &mut [u8], c: u8) {
+        for b in s {
+            *b = c;
+        }
+    }
+
+    fn reference_copy(dest: &mut [u8], src: &[u8]) {
+        for (d, s) in dest.iter_mut().zip(src) {
+            *d = *s;
+        }
+    }
+
+    // A pattern that differs at every offset, so a misplaced word shows up
+    fn pattern(buf: &mut [u8], seed: u8) {
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = (i as u8).wrapping_mul(7).wrapping_add(seed);
+        }
+    }
+
+    // Lengths around the word size and alignments on either side of it, each
+    // checked against the bytes next to the region too
+    const LENGTHS: [usize; 9] = [0, 1, 7, 8, 9, 15, 16, 17, 100];
+
+    #[test_case]
+    fn memset_matches_reference() {
+        for offset in 0..WORD {
+            for len in LENGTHS {
+                let mut buf = [0u8; 128];
+                let mut expected = [0u8; 128];
+                pattern(&mut buf, 1);
+                pattern(&mut expected, 1);
+                unsafe { memset(buf.as_mut_ptr().add(offset), 0x1A5, len); }
+                reference_set(&mut expected[offset..offset + len], 0xA5);
+                assert!(buf == expected, "memset offset {} len {}", offset, len);
+            }
+        }
+    }
+
+    #[test_case]
+    fn memcpy_matches_reference() {
+        for dest_offset in 0..WORD {
+            for src_offset in 0..WORD {
+                for len in LENGTHS {
+                    let mut src = [0u8; 128];
+                    pattern(&mut src, 3);
+                    let mut buf = [0u8; 128];
+                    let mut expected = [0u8; 128];
+                    unsafe { memcpy(buf.as_mut_ptr().add(dest_offset), src.as_ptr().add(src_offset), len); }
+                    reference_copy(&mut expected[dest_offset..dest_offset + len], &src[src_offset..]);
+                    assert!(buf == expected, "memcpy dest {} src {} len {}", dest_offset, src_offset, len);
+                }
+            }
+        }
+    }
+
+    // Moves `len` bytes from `from` to `to` within one pattern-filled buffer
+    // and checks the result against a copy made through a separate buffer.
+    fn check_memmove(from: usize, to: usize, len: usize) {
+        let mut buf = [0u8; 64];
+        pattern(&mut buf, 5);
+        let mut expected = buf;
+        let mut saved = [0u8; 64];
+        saved[..len].copy_from_slice(&buf[from..from + len]);
+        reference_copy(&mut expected[to..to + len], &saved[..len]);
+        unsafe { memmove(buf.as_mut_ptr().add(to), buf.as_ptr().add(from), len); }
+        assert!(buf == expected, "memmove from {} to {} len {}", from, to, len);
+    }
+
+    #[test_case]
+    fn memmove_overlapping_forward() {
+        // destination below the source, like scrolling rows up
+        for shift in 1..9 {
+            check_memmove(shift, 0, 40);
+        }
+    }
+
+    #[test_case]
+    fn memmove_overlapping_backward() {
+        // destination above the source: a forward copy would repeat bytes
+        for shift in 1..9 {
+            check_memmove(0, shift, 40);
+        }
+    }
+
+    #[test_case]
+    fn memmove_same_and_disjoint() {
+        check_memmove(10, 10, 20);
+        check_memmove(0, 32, 20);
+        check_memmove(40, 0, 0);
+    }
+
+    #[test_case]
+    fn parse_u32_edge_cases() {
+        assert_eq!(parse_u32(b""), None);
+        assert_eq!(parse_u32(b"   "), None);
+        assert_eq!(parse_u32(b"0"), Some(0));
+        assert_eq!(parse_u32(b"  42 "), Some(42));
+        assert_eq!(parse_u32(b"4 2"), None);
+        assert_eq!(parse_u32(b"-1"), None);
+        assert_eq!(parse_u32(b"12a"), None);
+        assert_eq!(parse_u32(b"4294967295"), Some(u32::MAX));
+        assert_eq!(parse_u32(b"4294967296"), None);
+        assert_eq!(parse_u32(b"99999999999"), None);
+    }
+
+    #[test_case]
+    fn parse_hex_edge_cases() {
+        assert_eq!(parse_hex(b""), None);
+        assert_eq!(parse_hex(b"0x"), None);
+        assert_eq!(parse_hex(b" ff "), Some(0xFF));
+        assert_eq!(parse_hex(b"0X1A"), Some(0x1A));
+        assert_eq!(parse_hex(b"0x400000"), Some(0x40_0000));
+        assert_eq!(parse_hex(b"fg"), None);
+        assert_eq!(parse_hex(b"ffffffff"), Some(u32::MAX));
+        assert_eq!(parse_hex(b"100000000"), None);
+    }
+
+    // Runs a command line with its normal output captured instead of shown.
+    // Errors still go to the screen.
+    fn exec(cmd: &[u8]) -> Capture {
+        let mut capture = Capture { buf: [0; MAX_FILE_SIZE], len: 0, overflow: false };
+        let mut ctx = ShellCtx { row: 1, sink: Some(&mut capture) };
+        run_command(&mut ctx, cmd);
+        capture
+    }
+
+    fn output(capture: &Capture) -> &[u8] {
+        &capture.buf[..capture.len]
+    }
+
+    fn slot_of(name: &[u8]) -> Option<usize> {
+        let dir = unsafe { &*core::ptr::addr_of!(DIR_STORAGE[CURRENT_DIR_IDX]) };
+        dir.files.iter().position(|f| f.is_some_and(|f| name_eq(&f.name, name)))
+    }
+
+    #[test_case]
+    fn touch_prefix_names_are_distinct() {
+        unsafe { init_filesystem(); }
+        exec(b"touch ab");
+        exec(b"touch abc");
+        assert_eq!(unsafe { TOTAL_FILES }, 2);
+        let (ab, abc) = (slot_of(b"ab"), slot_of(b"abc"));
+        assert!(ab.is_some() && abc.is_some() && ab != abc);
+    }
+
+    #[test_case]
+    fn names_may_contain_spaces() {
+        unsafe { init_filesystem(); }
+        exec(b"touch my file.txt");
+        assert!(slot_of(b"my file.txt").is_some());
+        assert!(slot_of(b"my").is_none());
+        exec(b"write \"my file.txt\" hello");
+        assert_eq!(output(&exec(b"cat my file.txt")), b"hello\n");
+        exec(b"touch my");
+        assert_eq!(unsafe { TOTAL_FILES }, 2);
+    }
+
+    #[test_case]
+    fn quoted_names_with_spaces_are_one_operand() {
+        unsafe { init_filesystem(); }
+        exec(b"write \"my notes.txt\" buy milk");
+        exec(b"cp \"my notes.txt\" copy.txt");
+        assert_eq!(file_len(b"copy.txt"), Some(8));
+        assert_eq!(output(&exec(b"grep milk \"my notes.txt\"")), b"buy milk\n");
+        exec(b"echo eggs >> my notes.txt");
+        assert_eq!(file_len(b"my notes.txt"), Some(13));
+    }
+
+    fn unhex(dump: &[&[u8]], out: &mut [u8]) -> Result<usize, &'static str> {
+        let (mut out_len, mut offsets) = (0, false);
+        for line in dump {
+            unhex_line(line, out, &mut out_len, &mut offsets)?;
+        }
+        Ok(out_len)
+    }
+
+    #[test_case]
+    fn unhex_hexdump_c_ends_at_final_offset() {
+        let mut out = [0u8; 16];
+        let dump: [&[u8]; 2] = [b"00000000  68 65 6c 6c 6f 0a                 |hello.|", b"00000006"];
+        assert_eq!(unhex(&dump, &mut out), Ok(6));
+        assert_eq!(&out[..6], b"hello\n");
+    }
+
+    #[test_case]
+    fn unhex_xxd_and_plain() {
+        let mut out = [0u8; 16];
+        assert_eq!(unhex(&[b"00000000: 6865 6c6c 6f0a  hello."], &mut out), Ok(6));
+        assert_eq!(&out[..6], b"hello\n");
+        // a plain line that happens to be 8 digits long is still data
+        assert_eq!(unhex(&[b"68656c6c"], &mut out), Ok(4));
+        assert_eq!(&out[..4], b"hell");
+    }
+
+    #[test_case]
+    fn unhex_rejects_repeat_marker_and_bad_digits() {
+        let mut out = [0u8; 64];
+        let row: &[u8] = b"00000000  00 00 00 00 00 00 00 00  00 00 00 00 00 00 00 00  |................|";
+        let dump = [row, b"*"];
+        assert!(unhex(&dump, &mut out).is_err_and(|msg| msg.contains('*')));
+        assert_eq!(unhex(&[b"6g"], &mut out), Err("Invalid hex dump"));
+    }
+
+    // Text on a screen row, e.g. to see the error a command printed
+    fn screen_row(row: usize) -> [u8; WIDTH] {
+        let mut text = [0u8; WIDTH];
+        for (col, c) in text.iter_mut().enumerate() {
+            *c = unsafe { *VGA_BUFFER.add((row * WIDTH + col) * 2) };
+        }
+        text
+    }
+
+    #[test_case]
+    fn write_to_full_directory_leaves_no_trace() {
+        unsafe { init_filesystem(); }
+        for i in 0..MAX_FILES {
+            let mut cmd = LineBuf::new();
+            let _ = write!(cmd, "touch f{}.txt", i);
+            exec(cmd.as_bytes());
+        }
+        let (files, index) = unsafe { (TOTAL_FILES, *core::ptr::addr_of!(FILE_INDEX)) };
+        assert_eq!(files, MAX_FILES);
+        let captured = exec(b"write new.txt hello");
+        assert!(screen_row(1).starts_with(b"Directory full"));
+        assert_eq!(output(&captured), b"");
+        assert!(slot_of(b"new.txt").is_none());
+        assert_eq!(unsafe { TOTAL_FILES }, files);
+        assert!(unsafe { *core::ptr::addr_of!(FILE_INDEX) } == index);
+        assert_eq!(unsafe { FREE_BLOCKS }, NUM_BLOCKS);
+    }
+
+    #[test_case]
+    fn long_redirect_target_is_refused() {
+        unsafe { init_filesystem(); }
+        exec(b"echo hi >> meeting-notes.txt");
+        assert!(screen_row(1).starts_with(b"Name too long"));
+        assert_eq!(unsafe { TOTAL_FILES }, 0);
+    }
+
+    fn file_len(name: &[u8]) -> Option<usize> {
+        unsafe { find_file(&*core::ptr::addr_of!(DIR_STORAGE[CURRENT_DIR_IDX]), name).map(|f| f.len) }
+    }
+
+    #[test_case]
+    fn write_without_text_empties_file() {
+        unsafe { init_filesystem(); }
+        exec(b"write a.txt hello");
+        assert_eq!(file_len(b"a.txt"), Some(5));
+        exec(b"write a.txt");
+        assert_eq!(file_len(b"a.txt"), Some(0));
+    }
+
+    #[test_case]
+    fn write_with_trailing_space_empties_file() {
+        unsafe { init_filesystem(); }
+        exec(b"write a.txt hello");
+        exec(b"write a.txt ");
+        assert_eq!(file_len(b"a.txt"), Some(0));
+        assert_eq!(unsafe { TOTAL_FILES }, 1);
+    }
+
+    #[test_case]
+    fn escape_empties_input_line() {
+        let mut ctx = ShellCtx { row: 1, sink: None };
+        let mut input = InputLine::new();
+        for &c in b"ls -l" {
+            let (key, shift) = ascii_to_scancode(c).unwrap();
+            input.key(&mut ctx, 2, key, shift, false, true);
+        }
+        assert_eq!(&input.buf[..input.len], b"ls -l");
+        input.key(&mut ctx, 2, 0x01, false, false, true);
+        assert_eq!((input.len, input.cursor), (0, 0));
+        assert!(screen_row(1)[2..8].iter().all(|&c| c == b' '));
+    }
+
+    #[test_case]
+    fn lines_strip_crlf() {
+        let mut it = lines(b"one\r\ntwo\r\n\r\nthree");
+        assert_eq!(it.next(), Some(&b"one"[..]));
+        assert_eq!(it.next(), Some(&b"two"[..]));
+        assert_eq!(it.next(), Some(&b""[..]));
+        assert_eq!(it.next(), Some(&b"three"[..]));
+        assert_eq!(it.next(), None);
+    }
+
+    #[test_case]
+    fn cat_hides_carriage_returns() {
+        unsafe {
+            init_filesystem();
+            let dir = &mut *core::ptr::addr_of_mut!(DIR_STORAGE[0]);
+            let slot = create_file(dir, b"dos.txt").unwrap();
+            file_write(dir.files[slot].as_mut().unwrap(), b"a=1\r\nb=2\r\n");
+        }
+        assert_eq!(output(&exec(b"cat dos.txt")), b"a=1\nb=2\n");
+    }
+
+    // Queues the make and break codes that type `text`, as the keyboard
+    // interrupt would
+    fn type_keys(text: &[u8]) {
+        for &c in text {
+            let (key, shift) = if c == b'\n' { (0x1C, false) } else { ascii_to_scancode(c).unwrap() };
+            if shift {
+                push_scancode(0x2A);
+            }
+            push_scancode(key);
+            push_scancode(key | 0x80);
+            if shift {
+                push_scancode(0xAA);
+            }
+        }
+    }
+
+    // Drains every queued key, keyboard and serial, into the text it types
+    // (Enter as a newline). Returns the length.
+    fn typed_text(buf: &mut [u8]) -> usize {
+        let mut len = 0;
+        while let Some(scancode) = pop_scancode() {
+            let c = match scancode {
+                0x2A | 0x36 => None,
+                0x1C => Some(b'\n'),
+                _ if scancode & 0x80 != 0 => None,
+                _ => typed_char(scancode, SHIFT_HELD.load(Ordering::Relaxed)),
+            };
+            if let Some(c) = c {
+                buf[len] = c;
+                len += 1;
+            }
+        }
+        len
+    }
+
+    #[test_case]
+    fn keys_typed_during_sleep_are_kept() {
+        let mut buf = [0u8; 64];
+        typed_text(&mut buf);
+        // a task types while sleep yields to it, like keys pressed meanwhile
+        unsafe { spawn_task("typist", || type_keys(b"ls\n")).unwrap(); }
+        let start = ticks();
+        exec(b"sleep 1");
+        assert!(ticks() - start >= TICKS_PER_SEC);
+        let len = typed_text(&mut buf);
+        assert_eq!(&buf[..len], b"ls\n");
+    }
+
+    #[test_case]
+    fn serial_paste_keeps_every_line() {
+        let mut buf = [0u8; 128];
+        typed_text(&mut buf);
+        // CR LF, LF and CR line ends, as different terminals send them
+        let paste = b"write a.txt Hi there\r\nls\npwd\rcat a.txt\r\n";
+        for &byte in paste {
+            push_serial(byte);
+        }
+        let len = typed_text(&mut buf);
+        assert_eq!(&buf[..len], b"write a.txt Hi there\nls\npwd\ncat a.txt\n");
+    }
+
+    #[test_case]
+    fn keys_typed_before_edit_reach_the_editor() {
+        unsafe {
+            init_filesystem();
+            *core::ptr::addr_of_mut!(EDIT) = None;
+        }
+        let mut buf = [0u8; 64];
+        typed_text(&mut buf);
+        // typed right after `edit`, before the editor has drawn: text, F2
+        // to save, Esc to leave
+        type_keys(b"hi\nthere");
+        for scancode in [0x3C, 0xBC, 0x01, 0x81] {
+            push_scancode(scancode);
+        }
+        exec(b"edit new.txt");
+        assert_eq!(output(&exec(b"cat new.txt")), b"hi\nthere\n");
+    }
+
+    #[test_case]
+    fn name_eq_ends_names_only_at_nul() {
+        assert!(name_eq(b"ab\0\0", b"ab"));
+        assert!(!name_eq(b"ab\0\0", b"abc"));
+        assert!(!name_eq(b"abc\0", b"ab"));
+        assert!(!name_eq(b"my file\0", b"my"));
+        assert!(name_eq(b"my file\0", b"my file"));
+        // stored names padded differently still match each other
+        assert!(name_eq(b"a.txt\0\0\0", b"a.txt\0"));
+    }
+
+    #[test_case]
+    fn held_caps_lock_toggles_once() {
+        let before = CAPS_LOCK.load(Ordering::Relaxed);
+        // auto-repeat: three make codes, then the release
+        for scancode in [0x3A, 0x3A, 0x3A, 0xBA] {
+            push_scancode(scancode);
+        }
+        while pop_scancode().is_some() {}
+        assert_eq!(CAPS_LOCK.load(Ordering::Relaxed), !before);
+        push_scancode(0x3A);
+        push_scancode(0xBA);
+        while pop_scancode().is_some() {}
+        assert_eq!(CAPS_LOCK.load(Ordering::Relaxed), before);
+    }
+}